@@ -1,61 +1,79 @@
-use crate::map::Map;
-use crate::tile::PowerUpType;
+use crate::map::TileGrid;
+use crate::rules::Ruleset;
 use crate::tile::TileType;
-use crate::tile::Tile;
 use bevy::prelude::*;
+use rand::seq::SliceRandom;
+use rand::Rng;
+use std::cmp::Ordering;
 
-const PROB_FIREPOWER: f32 = 0.08;
-const PROB_EXTRABOMB: f32 = 0.08;
+// Size of the maps played by the game and the server
+pub const MAP_DIMENSIONS: (usize, usize) = (19, 13);
+
+const NUM_QUADRANTS: usize = 4;
 
 fn within_distance_of_spawn_points(pos: IVec2, spawn_points: &[IVec2], distance: i32) -> bool {
     spawn_points.iter().any(|spawn_point| spawn_point.distance_squared(pos) <= distance * distance)
 }
 
-pub fn make_really_basic_map1(commands: &mut Commands) -> Map {
-    let mut map = Map::new_empty(commands, 3,3);
+pub fn make_really_basic_map1() -> TileGrid {
+    let mut map = TileGrid::filled(3, 3, TileType::Empty);
     map.set_spawn_points(vec![
         IVec2::new(1, 1),
         IVec2::new(1, 1),
     ]);
 
     for pos in map.pos_iter() {
-        let tile_type = if map.is_edge(pos) {
+        map[pos] = if map.is_edge(pos) {
             TileType::SolidWall
         } else {
             TileType::Empty
         };
-        commands.entity(map[pos]).insert(Tile { tile_type });
     }
 
     map
 }
 
-pub fn make_really_basic_map2(commands: &mut Commands) -> Map {
-    let mut map = Map::new_empty(commands, 5,5);
+pub fn make_really_basic_map2() -> TileGrid {
+    let mut map = TileGrid::filled(5, 5, TileType::Empty);
     map.set_spawn_points(vec![
         IVec2::new(1, 1),
         IVec2::new(1, 1),
     ]);
 
     for pos in map.pos_iter() {
-        let tile_type = if map.is_edge(pos) || (pos.x == 2 && pos.y == 2) {
+        map[pos] = if map.is_edge(pos) || (pos.x == 2 && pos.y == 2) {
             TileType::SolidWall
         } else {
             TileType::Empty
         };
-        commands.entity(map[pos]).insert(Tile { tile_type });
     }
 
     map
 }
 
-pub fn make_basic_map(commands: &mut Commands, width: usize, height: usize) -> Map {
-    let mut map = Map::new_empty(commands, width, height);
+pub fn make_basic_map(width: usize, height: usize, rng: &mut impl Rng) -> TileGrid {
+    let rules = Ruleset {
+        map_width: width,
+        map_height: height,
+        ..Ruleset::classic()
+    };
+    make_basic_map_with_rules(&rules, rng)
+}
+
+// A basic map with the size, walls and power-ups that the rules ask for
+pub fn make_basic_map_with_rules(rules: &Ruleset, rng: &mut impl Rng) -> TileGrid {
+    let (width, height) = (rules.map_width, rules.map_height);
+    let mut map = TileGrid::filled(width, height, TileType::Empty);
     map.set_spawn_points(vec![
         IVec2::new(1, 1),
         IVec2::new(1, height as i32 - 2),
         IVec2::new(width as i32 - 2, 1),
         IVec2::new(width as i32 - 2, height as i32 - 2),
+        // Extra spawn points in the middle of each side, for up to 8 players
+        IVec2::new(width as i32 / 2, 1),
+        IVec2::new(width as i32 / 2, height as i32 - 2),
+        IVec2::new(1, height as i32 / 2),
+        IVec2::new(width as i32 - 2, height as i32 / 2),
     ]);
 
     for pos in map.pos_iter() {
@@ -63,20 +81,146 @@ pub fn make_basic_map(commands: &mut Commands, width: usize, height: usize) -> M
             TileType::SolidWall
         } else if pos.x % 2 == 0 && pos.y % 2 == 0 {
             TileType::SolidWall
-        } else if rand::random::<f32>() < 0.8 && !within_distance_of_spawn_points(pos, &map.spawn_points(), 1) {
-            let contents = if rand::random::<f32>() < PROB_FIREPOWER {
-                Box::new(TileType::PowerUp(PowerUpType::Firepower))
-            } else if rand::random::<f32>() < PROB_EXTRABOMB {
-                Box::new(TileType::PowerUp(PowerUpType::ExtraBomb))
+        } else if rng.gen::<f32>() < rules.breakable_walls
+            && !within_distance_of_spawn_points(pos, &map.spawn_points(), 1)
+        {
+            // Counted power-ups are hidden once all the walls are up
+            let chances = if rules.power_up_counts.is_empty() {
+                &rules.power_ups[..]
             } else {
-                Box::new(TileType::Empty)
+                &[]
             };
-            TileType::BreakableWall(contents)
+            let contents = chances
+                .iter()
+                .find(|(_, chance)| rng.gen::<f32>() < *chance)
+                .map_or(TileType::Empty, |(power_up, _)| TileType::PowerUp(*power_up));
+            TileType::BreakableWall(Box::new(contents))
         } else {
             TileType::Empty
         };
-        commands.entity(map[pos]).insert(Tile { tile_type });
+        map[pos] = tile_type;
+    }
+    if !rules.power_up_counts.is_empty() {
+        hide_power_up_counts(&mut map, rules, rng);
     }
 
     map
 }
+
+// Hides exactly `rules.power_up_counts` of each power-up in random breakable
+// walls, as many as there are walls for
+fn hide_power_up_counts(map: &mut TileGrid, rules: &Ruleset, rng: &mut impl Rng) {
+    let (width, height) = (map.width() as i32, map.height() as i32);
+    // Walls on a centre line are as near to one side as to the other, so
+    // they're left for once the quadrants are full
+    let quadrant = |pos: IVec2| {
+        let side = |coord: i32, size: i32| (2 * coord + 1).cmp(&size);
+        match (side(pos.x, width), side(pos.y, height)) {
+            (Ordering::Equal, _) | (_, Ordering::Equal) => NUM_QUADRANTS,
+            (x, y) => (x == Ordering::Greater) as usize + 2 * (y == Ordering::Greater) as usize,
+        }
+    };
+
+    // A pool of walls for each quadrant, then one for the rest
+    let mut pools = vec![Vec::new(); NUM_QUADRANTS + 1];
+    for pos in map.pos_iter() {
+        if matches!(map[pos], TileType::BreakableWall(_)) {
+            let pool = if rules.balance_quadrants { quadrant(pos) } else { NUM_QUADRANTS };
+            pools[pool].push(pos);
+        }
+    }
+    for pool in &mut pools {
+        pool.shuffle(rng);
+    }
+
+    // Deal each power-up out to the quadrants in turn, starting from a random
+    // one so that no quadrant is always first to get the odd ones
+    for (power_up, count) in &rules.power_up_counts {
+        let first_quadrant = rng.gen_range(0..NUM_QUADRANTS);
+        for dealt in 0..*count as usize {
+            let Some(pos) = (0..NUM_QUADRANTS)
+                .map(|offset| (first_quadrant + dealt + offset) % NUM_QUADRANTS)
+                .chain([NUM_QUADRANTS])
+                .find_map(|pool| pools[pool].pop())
+            else {
+                return;
+            };
+            map[pos] = TileType::BreakableWall(Box::new(TileType::PowerUp(*power_up)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::MapSeed;
+    use crate::tile::PowerUpType;
+
+    #[test]
+    fn test_seeded_map_is_reproducible() {
+        let make = |seed| {
+            let map = make_basic_map(19, 13, &mut MapSeed(seed).rng());
+            map.pos_iter().map(|pos| map[pos].clone()).collect::<Vec<_>>()
+        };
+
+        assert_eq!(make(42), make(42));
+        assert_ne!(make(42), make(43));
+    }
+
+    #[test]
+    fn test_power_up_counts_are_exact_and_balanced() {
+        let rules = Ruleset {
+            power_up_counts: vec![
+                (PowerUpType::Firepower, 10),
+                (PowerUpType::ExtraBomb, 8),
+                (PowerUpType::Kick, 2),
+            ],
+            balance_quadrants: true,
+            ..Ruleset::classic()
+        };
+        let half_width = rules.map_width as i32 / 2;
+        let half_height = rules.map_height as i32 / 2;
+        let quadrant_of = |pos: &IVec2| {
+            (pos.x != half_width && pos.y != half_height)
+                .then_some((pos.x > half_width) as usize + 2 * (pos.y > half_height) as usize)
+        };
+        let mut kick_quadrants = Vec::new();
+        for seed in 0..8 {
+            let map = make_basic_map_with_rules(&rules, &mut MapSeed(seed).rng());
+            let hidden: Vec<(IVec2, PowerUpType)> = map
+                .pos_iter()
+                .filter_map(|pos| match &map[pos] {
+                    TileType::BreakableWall(contents) => match **contents {
+                        TileType::PowerUp(power_up) => Some((pos, power_up)),
+                        _ => None,
+                    },
+                    _ => None,
+                })
+                .collect();
+            // Every quarter of the map gets an even share of each power-up
+            for (power_up, count) in &rules.power_up_counts {
+                let mut per_quadrant = [0; NUM_QUADRANTS];
+                for (pos, _) in hidden.iter().filter(|(_, hidden)| hidden == power_up) {
+                    per_quadrant[quadrant_of(pos).unwrap()] += 1;
+                }
+                assert_eq!(per_quadrant.iter().sum::<u32>(), *count);
+                let most = per_quadrant.iter().max().unwrap();
+                let spread = most - per_quadrant.iter().min().unwrap();
+                assert!(spread <= 1, "seed {} {:?} {:?}", seed, power_up, per_quadrant);
+            }
+
+            let mut kicks: Vec<usize> = hidden
+                .iter()
+                .filter(|(_, hidden)| *hidden == PowerUpType::Kick)
+                .filter_map(|(pos, _)| quadrant_of(pos))
+                .collect();
+            kicks.sort();
+            kick_quadrants.push(kicks);
+        }
+
+        // Which quadrants get the odd ones changes from map to map
+        kick_quadrants.sort();
+        kick_quadrants.dedup();
+        assert!(kick_quadrants.len() > 1);
+    }
+}
@@ -0,0 +1,71 @@
+use killer_critters::{rules::*, server::*, sim::*};
+use std::net::UdpSocket;
+use std::time::{Duration, Instant};
+
+const DEFAULT_PORT: u16 = 7000;
+
+// The value following a cmdline arg, e.g. `--port 7000`
+fn arg_value(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != name);
+    args.next()?;
+    args.next()
+}
+
+fn main() {
+    if std::env::args().any(|arg| arg == "--help") {
+        println!(
+            "Usage: killer-critters-server [--port <number>] [--seed <number>] [--rules {}|<file>]",
+            PRESET_NAMES.join("|")
+        );
+        return;
+    }
+
+    let port = match arg_value("--port").map(|port| port.parse()) {
+        None => DEFAULT_PORT,
+        Some(Ok(port)) => port,
+        Some(Err(_)) => {
+            eprintln!("Invalid port");
+            std::process::exit(1);
+        }
+    };
+
+    let seed = match arg_value("--seed").map(|seed| seed.parse()) {
+        None => None,
+        Some(Ok(seed)) => Some(seed),
+        Some(Err(_)) => {
+            eprintln!("Invalid seed");
+            std::process::exit(1);
+        }
+    };
+
+    let rules = match arg_value("--rules").map(|name| Ruleset::from_preset_or_file(&name)) {
+        None => Ruleset::default(),
+        Some(Ok(rules)) => rules,
+        Some(Err(err)) => {
+            eprintln!("Invalid rules: {}", err);
+            std::process::exit(1);
+        }
+    };
+
+    let server =
+        UdpSocket::bind(("0.0.0.0", port)).and_then(|socket| GameServer::new(socket, seed, rules));
+    let mut server = match server {
+        Ok(server) => server,
+        Err(err) => {
+            eprintln!("Unable to serve on port {}: {}", port, err);
+            std::process::exit(1);
+        }
+    };
+    println!("Listening on port {}", port);
+
+    // Step at the same fixed rate as the game, catching up if a tick ran late
+    let tick_duration = Duration::from_secs_f64(1.0 / TICKS_PER_SECOND as f64);
+    let mut next_tick = Instant::now();
+    loop {
+        server.tick();
+        next_tick += tick_duration;
+        if let Some(wait) = next_tick.checked_duration_since(Instant::now()) {
+            std::thread::sleep(wait);
+        }
+    }
+}
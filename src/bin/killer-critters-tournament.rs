@@ -0,0 +1,124 @@
+use killer_critters::{basic::*, bot::*, map::*, map_file::*, rules::*, sim::*, tournament::*};
+use std::path::Path;
+
+const DEFAULT_MATCHES: u32 = 1000;
+const DEFAULT_PLAYERS: usize = 4;
+const DEFAULT_MAX_SECONDS: u64 = 180;
+
+// The value following a cmdline arg, e.g. `--matches 1000`
+fn arg_value(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != name);
+    args.next()?;
+    args.next()
+}
+
+fn parsed_arg<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match arg_value(name).map(|value| value.parse()) {
+        None => default,
+        Some(Ok(value)) => value,
+        Some(Err(_)) => {
+            eprintln!("Invalid {}", name);
+            std::process::exit(1);
+        }
+    }
+}
+
+fn main() {
+    if std::env::args().any(|arg| arg == "--help") {
+        println!(
+            "Usage: killer-critters-tournament [--matches <count>] [--players <count>] \
+             [--bot-difficulty easy|normal|hard] [--map <file>] [--seed <first seed>] \
+             [--max-seconds <seconds>] [--rules {}|<file>]",
+            PRESET_NAMES.join("|")
+        );
+        return;
+    }
+
+    let matches: u32 = parsed_arg("--matches", DEFAULT_MATCHES);
+    let num_players: usize = parsed_arg("--players", DEFAULT_PLAYERS);
+    let first_seed: u64 = parsed_arg("--seed", 0);
+    let max_ticks = parsed_arg("--max-seconds", DEFAULT_MAX_SECONDS) * TICKS_PER_SECOND as u64;
+    let difficulty = match arg_value("--bot-difficulty") {
+        None => BotDifficulty::default(),
+        Some(name) => BotDifficulty::from_name(&name).unwrap_or_else(|| {
+            eprintln!("Invalid bot difficulty `{}`", name);
+            std::process::exit(1);
+        }),
+    };
+    let rules = match arg_value("--rules") {
+        None => Ruleset::default(),
+        Some(name) => Ruleset::from_preset_or_file(&name).unwrap_or_else(|err| {
+            eprintln!("Unable to load rules `{}`: {}", name, err);
+            std::process::exit(1);
+        }),
+    };
+
+    // Either every match is on the map file, or each makes a basic map from
+    // the next seed
+    let map_file = arg_value("--map").map(|path| match load_map(Path::new(&path)) {
+        Ok(map) => map,
+        Err(err) => {
+            eprintln!("Unable to load map `{}`: {}", path, err);
+            std::process::exit(1);
+        }
+    });
+    let num_spawn_points = map_file.as_ref().map_or(8, |map| map.spawn_points().len());
+    if num_players < 2 || num_players > num_spawn_points {
+        eprintln!("Matches need between 2 and {} players", num_spawn_points);
+        std::process::exit(1);
+    }
+    // Matches on a map file still each get a seed of their own, which decides
+    // the order the bots try directions in as well as curses
+    let make_map = |index: u32| match &map_file {
+        Some(map) => map.clone(),
+        None => make_basic_map_with_rules(&rules, &mut MapSeed(first_seed + index as u64).rng()),
+    };
+
+    // Matches are independent, so spread them over every core
+    let num_threads = std::thread::available_parallelism().map_or(1, |n| n.get()) as u32;
+    println!(
+        "Playing {} matches of {} {:?} bots by the {} rules on {} threads",
+        matches, num_players, difficulty, rules.name, num_threads
+    );
+    let last_seed = first_seed + matches.saturating_sub(1) as u64;
+    match arg_value("--map") {
+        Some(path) => println!(
+            "Every match is on `{}`, varied by seeds {} to {}",
+            path, first_seed, last_seed
+        ),
+        None => println!(
+            "Each match is on a basic map from seeds {} to {}",
+            first_seed, last_seed
+        ),
+    }
+    let stats = std::thread::scope(|scope| {
+        let threads: Vec<_> = (0..num_threads)
+            .map(|thread| {
+                let make_map = &make_map;
+                let rules = &rules;
+                scope.spawn(move || {
+                    let mut stats = TournamentStats::default();
+                    for index in (thread..matches).step_by(num_threads as usize) {
+                        stats.add(&play_bot_match(
+                            make_map(index),
+                            first_seed + index as u64,
+                            num_players,
+                            difficulty,
+                            max_ticks,
+                            rules,
+                        ));
+                    }
+                    stats
+                })
+            })
+            .collect();
+
+        let mut stats = TournamentStats::default();
+        for thread in threads {
+            stats.merge(&thread.join().unwrap());
+        }
+        stats
+    });
+
+    print!("{}", stats);
+}
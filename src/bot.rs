@@ -0,0 +1,268 @@
+use crate::danger::{blast_line, blast_times};
+use crate::map::{Grid, TileGrid};
+use crate::nav::{bfs, bfs_in_order, DIRECTIONS};
+use crate::player::*;
+use crate::sim::*;
+use crate::tile::*;
+use bevy::prelude::*;
+use rand::seq::SliceRandom;
+
+// Roughly how long a player takes to cross one tile at the starting speed
+const TICKS_PER_TILE: u32 = (TILE_SIZE / (PLAYER_SPEED * TICK_SECONDS)) as u32;
+
+fn ticks_per_tile(player: &Player) -> u32 {
+    (TILE_SIZE / (player.current_speed() * TICK_SECONDS)) as u32
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default)]
+pub enum BotDifficulty {
+    Easy,
+    #[default]
+    Normal,
+    Hard,
+}
+
+impl BotDifficulty {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "easy" => Some(Self::Easy),
+            "normal" => Some(Self::Normal),
+            "hard" => Some(Self::Hard),
+            _ => None,
+        }
+    }
+
+    // Extra ticks the bot keeps between itself and any blast
+    fn safety_margin(self) -> u32 {
+        match self {
+            Self::Easy => 0,
+            Self::Normal => TICKS_PER_TILE / 4,
+            Self::Hard => TICKS_PER_TILE / 2,
+        }
+    }
+
+    fn hunts_players(self) -> bool {
+        self != Self::Easy
+    }
+
+    fn collects_power_ups(self) -> bool {
+        self != Self::Easy
+    }
+}
+
+// The order in which a bot tries directions when paths tie. Bots that meet on
+// a tile would otherwise move as one from then on, so each gets a different
+// rotation of an order drawn from the match's seed. That way no spawn point
+// keeps the same bias from one match to the next.
+fn direction_order(seed: u64, player_index: usize) -> [IVec2; 4] {
+    let mut directions = DIRECTIONS;
+    directions.shuffle(&mut tick_rng(seed, 0, BOT_STREAM, 0));
+    directions.rotate_left(player_index % DIRECTIONS.len());
+    directions
+}
+
+// The control of a bot player for the next tick. It depends only on the state
+// of the game, so every peer and every replay computes the same control.
+pub fn bot_control(sim: &GameSim, player_index: usize) -> PlayerControl {
+    let player = &sim.players()[player_index];
+    let PlayerController::Bot(difficulty) = player.controller else {
+        return PlayerControl::default();
+    };
+    let tiles = sim.tiles();
+    let Some(pos) = tiles.get_index_from_position(player.position) else {
+        return PlayerControl::default();
+    };
+    let margin = difficulty.safety_margin();
+    let pace = ticks_per_tile(player);
+    let fuse = player.bomb_fuse(sim.rules());
+    let burn = sim.rules().explosion_duration;
+    let mut blast = blast_times(tiles, player_index);
+    // Keep out from under a closing wall that's about to land
+    if let Some((wall, ticks_left)) = sim.next_wall_drop() {
+        let ticks_left = ticks_left.min(u32::MAX as u64) as u32;
+        if ticks_left <= 2 * pace + margin {
+            blast[wall] = Some(blast[wall].map_or(ticks_left, |time| time.min(ticks_left)));
+        }
+    }
+
+    let directions = direction_order(sim.seed(), player_index);
+    let search = |can_enter: &dyn Fn(IVec2, u32) -> bool, is_goal: &dyn Fn(IVec2) -> bool| {
+        bfs_in_order(tiles, pos, &directions, can_enter, is_goal)
+    };
+
+    // Get out of the way of any bomb that will reach us
+    if blast[pos].is_some() {
+        let escape = search(
+            &|next, tiles_away| is_safe(&blast, next, tiles_away, pace, margin, burn),
+            &|goal| blast[goal].is_none(),
+        );
+        return match escape {
+            Some(path) => move_along(player, &path),
+            None => PlayerControl::default(),
+        };
+    }
+
+    // Set off our remote bombs one at a time once we're clear of them
+    if player.has_remote && sim.next_remote_bomb(player_index).is_some() {
+        return PlayerControl {
+            motion: Vec2::ZERO,
+            action: PlayerAction::Detonate,
+        };
+    }
+
+    let enemies: Vec<IVec2> = sim
+        .players()
+        .iter()
+        .filter(|other| other.alive && other.player_index != player_index)
+        .filter_map(|other| tiles.get_index_from_position(other.position))
+        .collect();
+    let is_target = |from: IVec2| {
+        (difficulty.hunts_players()
+            && enemies
+                .iter()
+                .any(|enemy| in_blast(tiles, from, player, *enemy)))
+            || DIRECTIONS
+                .iter()
+                .any(|dir| tiles.contains(from + *dir) && is_breakable(&tiles[from + *dir]))
+    };
+
+    if is_target(pos)
+        && player.num_bombs > 0
+        && tiles[pos] == TileType::Empty
+        && can_escape_bomb(tiles, pos, player, fuse, pace, margin, burn)
+    {
+        return PlayerControl {
+            motion: Vec2::ZERO,
+            action: PlayerAction::DropBomb,
+        };
+    }
+
+    // Head for the nearest place worth bombing, never through a blast line
+    let path = search(&|next, _| blast[next].is_none(), &|goal| {
+        goal != pos
+            && ((difficulty.collects_power_ups() && matches!(tiles[goal], TileType::PowerUp(_)))
+                || (player.num_bombs > 0 && is_target(goal)))
+    });
+    // Otherwise close in on the nearest player
+    let path = path.or_else(|| {
+        difficulty.hunts_players().then_some(())?;
+        search(&|next, _| blast[next].is_none(), &|goal| {
+            goal != pos && enemies.contains(&goal)
+        })
+    });
+    match path {
+        Some(path) => move_along(player, &path),
+        None => PlayerControl::default(),
+    }
+}
+
+fn is_breakable(tile: &TileType) -> bool {
+    matches!(tile, TileType::BreakableWall(_))
+}
+
+fn in_blast(tiles: &TileGrid, bomb_pos: IVec2, player: &Player, target: IVec2) -> bool {
+    blast_line(tiles, bomb_pos, player.firepower, player.has_pierce).contains(&target)
+}
+
+// Whether a bot reaching `pos` after walking `tiles_away` tiles, taking `pace`
+// ticks for each, is clear of any blast there, burning for `burn` ticks, for
+// as long as it takes to cross it
+fn is_safe(
+    blast: &Grid<Option<u32>>,
+    pos: IVec2,
+    tiles_away: u32,
+    pace: u32,
+    margin: u32,
+    burn: u32,
+) -> bool {
+    let Some(time) = blast[pos] else {
+        return true;
+    };
+    let arrive = tiles_away
+        .saturating_mul(pace)
+        .saturating_sub(pace / 2 + margin);
+    let leave = tiles_away
+        .saturating_mul(pace)
+        .saturating_add(pace / 2 + margin);
+    time.saturating_add(burn) <= arrive || time > leave
+}
+
+fn can_escape_bomb(
+    tiles: &TileGrid,
+    pos: IVec2,
+    player: &Player,
+    fuse: u32,
+    pace: u32,
+    margin: u32,
+    burn: u32,
+) -> bool {
+    let mut tiles = tiles.clone();
+    let mut bomb = Bomb::new(fuse, player.firepower, usize::MAX);
+    bomb.pierce = player.has_pierce;
+    tiles[pos] = TileType::Bomb(Some(bomb));
+    let blast = blast_times(&tiles, player.player_index);
+    bfs(
+        &tiles,
+        pos,
+        |next, tiles_away| is_safe(&blast, next, tiles_away, pace, margin, burn),
+        |goal| blast[goal].is_none(),
+    )
+    .is_some()
+}
+
+// Walk towards the centre of the first tile of the path, which keeps the bot
+// lined up with the corridors
+fn move_along(player: &Player, path: &[IVec2]) -> PlayerControl {
+    let step = player.current_speed() * TICK_SECONDS;
+    let offset = path[0].as_vec2() - player.position;
+    PlayerControl {
+        motion: (offset / step).clamp_length_max(1.0),
+        action: PlayerAction::None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic::*;
+    use crate::map::MapSeed;
+
+    #[test]
+    fn test_bot_clears_walls_safely() {
+        let mut rng = MapSeed(11).rng();
+        let mut sim = GameSim::new(make_basic_map(MAP_DIMENSIONS.0, MAP_DIMENSIONS.1, &mut rng));
+        sim.add_player(PlayerController::Bot(BotDifficulty::Normal))
+            .unwrap();
+
+        let walls = |sim: &GameSim| {
+            let tiles = sim.tiles();
+            tiles
+                .pos_iter()
+                .filter(|pos| is_breakable(&tiles[*pos]))
+                .count()
+        };
+        let start_walls = walls(&sim);
+
+        // A bot only bombs when it can escape, so alone it never dies
+        for _ in 0..30 * TICKS_PER_SECOND {
+            sim.step(&[bot_control(&sim, 0)]);
+            assert!(sim.players()[0].alive, "bot died at tick {}", sim.tick());
+        }
+        assert!(walls(&sim) < start_walls - 5);
+    }
+
+    #[test]
+    fn test_direction_order_follows_seed() {
+        let orders: Vec<_> = (0..4).map(|player| direction_order(7, player)).collect();
+        for (index, order) in orders.iter().enumerate() {
+            assert!(!orders[..index].contains(order));
+        }
+        assert_eq!(direction_order(7, 2), orders[2]);
+
+        // The same spawn point tries directions in other orders in other matches
+        let mut seeded: Vec<_> = (0..16).map(|seed| direction_order(seed, 0)).collect();
+        seeded.sort_by_key(|order| order.map(|dir| (dir.x, dir.y)));
+        seeded.dedup();
+        assert!(seeded.len() > 1);
+    }
+}
@@ -0,0 +1,293 @@
+use crate::player::Player;
+use crate::sim::{GameSim, ThrownBomb};
+use crate::tile::*;
+use bevy::prelude::*;
+
+// 64-bit FNV-1a. Everything is written as explicit little-endian bytes, so
+// the result is the same on every platform and build.
+pub struct StableHasher(u64);
+
+impl Default for StableHasher {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl StableHasher {
+    pub fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.write(&[value]);
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.write(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write(&value.to_le_bytes());
+    }
+
+    // Compares bit for bit, so -0.0 and 0.0 hash differently
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_u32(value.to_bits());
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+fn hash_tile(hasher: &mut StableHasher, tile: &TileType) {
+    match tile {
+        TileType::Empty => hasher.write_u8(0),
+        TileType::SolidWall => hasher.write_u8(1),
+        TileType::BreakableWall(contents) => {
+            hasher.write_u8(2);
+            hash_tile(hasher, contents);
+        }
+        TileType::Bomb(None) => hasher.write_u8(3),
+        TileType::Bomb(Some(bomb)) => {
+            hasher.write_u8(4);
+            hash_bomb(hasher, bomb);
+        }
+        TileType::Explosion(remaining, contents) => {
+            hasher.write_u8(5);
+            hasher.write_u32(remaining.unwrap_or(u32::MAX));
+            hash_tile(hasher, contents);
+        }
+        TileType::PowerUp(PowerUpType::Firepower) => hasher.write_u8(6),
+        TileType::PowerUp(PowerUpType::ExtraBomb) => hasher.write_u8(7),
+        TileType::PowerUp(PowerUpType::Kick) => hasher.write_u8(8),
+        TileType::PowerUp(PowerUpType::Glove) => hasher.write_u8(9),
+        TileType::PowerUp(PowerUpType::Remote) => hasher.write_u8(10),
+        TileType::PowerUp(PowerUpType::Speed) => hasher.write_u8(11),
+        TileType::PowerUp(PowerUpType::Pierce) => hasher.write_u8(12),
+        TileType::PowerUp(PowerUpType::LineBomb) => hasher.write_u8(13),
+        TileType::PowerUp(PowerUpType::Skull) => hasher.write_u8(14),
+        TileType::PowerUp(PowerUpType::Shield) => hasher.write_u8(15),
+    }
+}
+
+fn hash_bomb(hasher: &mut StableHasher, bomb: &Bomb) {
+    hasher.write_u32(bomb.fuse);
+    hasher.write_u32(bomb.firepower as u32);
+    hasher.write_u32(bomb.player_index as u32);
+    hasher.write_u64(bomb.remote.unwrap_or(u64::MAX));
+    hasher.write_u8(bomb.pierce as u8);
+    match bomb.slide {
+        None => hasher.write_u8(0),
+        Some(slide) => {
+            hasher.write_u8(1);
+            hasher.write_u32(slide.direction.x as u32);
+            hasher.write_u32(slide.direction.y as u32);
+            hasher.write_u32(slide.progress);
+        }
+    }
+}
+
+// Bombs in the air are hashed with the player they belong to
+fn hash_player(hasher: &mut StableHasher, player: &Player, thrown_bombs: &[ThrownBomb]) {
+    hasher.write_u32(player.player_index as u32);
+    hasher.write_f32(player.position.x);
+    hasher.write_f32(player.position.y);
+    hasher.write_f32(player.facing.x);
+    hasher.write_f32(player.facing.y);
+    hasher.write_u32(player.num_bombs as u32);
+    hasher.write_u32(player.firepower as u32);
+    hasher.write_f32(player.speed);
+    hasher.write_u8(
+        player.is_moving as u8
+            | (player.alive as u8) << 1
+            | (player.can_kick as u8) << 2
+            | (player.can_throw as u8) << 3
+            | (player.has_remote as u8) << 4
+            | (player.has_pierce as u8) << 5
+            | (player.has_line_bomb as u8) << 6,
+    );
+    match player.curse {
+        None => hasher.write_u8(0),
+        Some(active) => {
+            hasher.write_u8(1 + active.curse as u8);
+            hasher.write_u32(active.remaining);
+        }
+    }
+    hasher.write_u32(player.shields);
+    hasher.write_u32(player.invulnerable);
+    hasher.write_u64(player.bombs_placed);
+    hasher.write_u32(player.power_ups.len() as u32);
+    for power_up in &player.power_ups {
+        hash_tile(hasher, &TileType::PowerUp(*power_up));
+    }
+    for thrown in thrown_bombs
+        .iter()
+        .filter(|thrown| thrown.bomb.player_index == player.player_index)
+    {
+        hash_bomb(hasher, &thrown.bomb);
+        hasher.write_u32(thrown.from.x as u32);
+        hasher.write_u32(thrown.from.y as u32);
+        hasher.write_u32(thrown.direction.x as u32);
+        hasher.write_u32(thrown.direction.y as u32);
+        hasher.write_u32(thrown.distance as u32);
+        hasher.write_u32(thrown.progress);
+        hasher.write_u32(thrown.travelled as u32);
+    }
+}
+
+fn element_hash(hash: impl FnOnce(&mut StableHasher)) -> u32 {
+    let mut hasher = StableHasher::default();
+    hash(&mut hasher);
+    let hash = hasher.finish();
+    (hash ^ (hash >> 32)) as u32
+}
+
+// A hash of every tile and every player of a game at one tick. Two games are
+// in the same state when their checksums match, and when they don't the
+// digests tell which tile or player is different.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StateDigest {
+    pub tick: u64,
+    pub map_width: usize,
+    pub map_height: usize,
+    // One hash per tile in `pos_iter` order, followed by one per player
+    pub elements: Vec<u32>,
+}
+
+// The first part of the game state where two digests disagree
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Difference {
+    Tick,
+    MapSize,
+    Tile(IVec2),
+    Player(usize),
+    NumPlayers,
+}
+
+impl std::fmt::Display for Difference {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Difference::Tick => write!(f, "tick"),
+            Difference::MapSize => write!(f, "map size"),
+            Difference::Tile(pos) => write!(f, "tile ({}, {})", pos.x, pos.y),
+            Difference::Player(player_index) => write!(f, "player {}", player_index),
+            Difference::NumPlayers => write!(f, "number of players"),
+        }
+    }
+}
+
+impl StateDigest {
+    pub fn of(sim: &GameSim) -> Self {
+        let tiles = sim.tiles();
+        let mut elements: Vec<u32> = tiles
+            .pos_iter()
+            .map(|pos| element_hash(|hasher| hash_tile(hasher, &tiles[pos])))
+            .collect();
+        elements.extend(
+            sim.players().iter().map(|player| {
+                element_hash(|hasher| hash_player(hasher, player, sim.thrown_bombs()))
+            }),
+        );
+
+        Self {
+            tick: sim.tick(),
+            map_width: tiles.width(),
+            map_height: tiles.height(),
+            elements,
+        }
+    }
+
+    pub fn checksum(&self) -> u64 {
+        let mut hasher = StableHasher::default();
+        hasher.write_u64(self.tick);
+        hasher.write_u32(self.map_width as u32);
+        hasher.write_u32(self.map_height as u32);
+        for element in &self.elements {
+            hasher.write_u32(*element);
+        }
+        hasher.finish()
+    }
+
+    fn num_tiles(&self) -> usize {
+        self.map_width * self.map_height
+    }
+
+    // What an element index refers to, following the order of `pos_iter`
+    fn element(&self, index: usize) -> Difference {
+        if index < self.num_tiles() {
+            let index = index as i32;
+            let height = self.map_height as i32;
+            Difference::Tile(IVec2::new(index / height, index % height))
+        } else {
+            Difference::Player(index - self.num_tiles())
+        }
+    }
+
+    pub fn first_difference(&self, other: &StateDigest) -> Option<Difference> {
+        if self.tick != other.tick {
+            return Some(Difference::Tick);
+        }
+        if (self.map_width, self.map_height) != (other.map_width, other.map_height) {
+            return Some(Difference::MapSize);
+        }
+
+        let differing = self
+            .elements
+            .iter()
+            .zip(&other.elements)
+            .position(|(a, b)| a != b);
+        match differing {
+            Some(index) => Some(self.element(index)),
+            None if self.elements.len() != other.elements.len() => Some(Difference::NumPlayers),
+            None => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic::*;
+    use crate::map::MapSeed;
+    use crate::player::*;
+
+    #[test]
+    fn test_digest_finds_difference() {
+        let tiles = make_basic_map(MAP_DIMENSIONS.0, MAP_DIMENSIONS.1, &mut MapSeed(7).rng());
+        let mut sim = GameSim::new(tiles);
+        sim.add_player(PlayerController::KeyboardArrows).unwrap();
+        sim.add_player(PlayerController::KeyboardWASD).unwrap();
+
+        let mut other = sim.clone();
+        assert_eq!(StateDigest::of(&sim), StateDigest::of(&other));
+        assert_eq!(
+            StateDigest::of(&sim).checksum(),
+            StateDigest::of(&other).checksum()
+        );
+
+        // Only the contents of a wall differ
+        let pos = IVec2::new(3, 2);
+        other.tiles_mut()[pos] =
+            TileType::BreakableWall(Box::new(TileType::PowerUp(PowerUpType::Firepower)));
+        sim.tiles_mut()[pos] = TileType::BreakableWall(Box::new(TileType::Empty));
+
+        let (a, b) = (StateDigest::of(&sim), StateDigest::of(&other));
+        assert_ne!(a.checksum(), b.checksum());
+        assert_eq!(a.first_difference(&b), Some(Difference::Tile(pos)));
+
+        // A player moving
+        let mut moved = sim.clone();
+        let right = PlayerControl {
+            motion: Vec2::X,
+            action: PlayerAction::None,
+        };
+        sim.step(&[]);
+        moved.step(&[PlayerControl::default(), right]);
+        let (a, b) = (StateDigest::of(&sim), StateDigest::of(&moved));
+        assert_eq!(a.first_difference(&b), Some(Difference::Player(1)));
+    }
+}
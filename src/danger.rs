@@ -0,0 +1,175 @@
+use crate::map::{Grid, TileGrid};
+use crate::nav::DIRECTIONS;
+use crate::tile::*;
+use bevy::prelude::*;
+
+// The tiles a bomb at `pos` would set alight if it exploded now. Blasts pass
+// through open tiles, bombs and explosions, and stop at walls, burning a
+// breakable wall but not going past it unless the bomb pierces.
+pub fn blast_line(tiles: &TileGrid, pos: IVec2, firepower: i32, pierce: bool) -> Vec<IVec2> {
+    blast_line_through(tiles, pos, firepower, pierce, |_| false)
+}
+
+fn blast_line_through(
+    tiles: &TileGrid,
+    pos: IVec2,
+    firepower: i32,
+    pierce: bool,
+    is_destroyed: impl Fn(IVec2) -> bool,
+) -> Vec<IVec2> {
+    let mut line = vec![pos];
+    for dir in DIRECTIONS {
+        for dist in 1..=firepower {
+            let pos = pos + dir * dist;
+            if !tiles.contains(pos) {
+                continue;
+            }
+            match tiles[pos] {
+                TileType::SolidWall => break,
+                TileType::BreakableWall(_) if !pierce && !is_destroyed(pos) => {
+                    line.push(pos);
+                    break;
+                }
+                _ => line.push(pos),
+            }
+        }
+    }
+    line
+}
+
+// For every tile, the number of `GameSim::step`s until an explosion covers it
+// if no more bombs are dropped: 0 while it's alight already, 1 if it goes up
+// during the next step and `None` if no bomb on the map will reach it.
+//
+// This follows `GameSim::map_transitions`: fuses burn down together, bombs
+// explode in tile order, a blast detonates any bomb it reaches, and a wall
+// destroyed earlier no longer stops later blasts.
+//
+// The times are as `player_index` sees them. Their own remote bombs wait for
+// them, but anyone else's could be set off at any time, so those count as
+// going up during the next step.
+pub fn blast_times(tiles: &TileGrid, player_index: usize) -> Grid<Option<u32>> {
+    let mut blast = Grid::filled(tiles.width(), tiles.height(), None);
+    let mut bomb_times: Grid<Option<u32>> = Grid::filled(tiles.width(), tiles.height(), None);
+    for pos in tiles.pos_iter() {
+        match &tiles[pos] {
+            TileType::Explosion(_, _) => blast[pos] = Some(0),
+            TileType::Bomb(Some(bomb))
+                if bomb.remote.is_some() && bomb.player_index != player_index =>
+            {
+                bomb_times[pos] = Some(1)
+            }
+            // A bomb explodes during the step in which its fuse reaches zero
+            TileType::Bomb(Some(bomb)) => bomb_times[pos] = Some(bomb.fuse.max(1)),
+            _ => {}
+        }
+    }
+
+    let mut exploded = Grid::filled(tiles.width(), tiles.height(), false);
+    let mut destroyed = Grid::filled(tiles.width(), tiles.height(), false);
+    loop {
+        // The simulation visits tiles column by column, as `pos_iter` does
+        let next = tiles
+            .pos_iter()
+            .filter(|pos| !exploded[*pos])
+            .filter_map(|pos| bomb_times[pos].map(|time| (time, pos)))
+            .min_by_key(|(time, pos)| (*time, pos.x, pos.y));
+        let Some((time, bomb_pos)) = next else {
+            break;
+        };
+        let TileType::Bomb(Some(bomb)) = &tiles[bomb_pos] else {
+            break;
+        };
+        exploded[bomb_pos] = true;
+
+        let line = blast_line_through(tiles, bomb_pos, bomb.firepower, bomb.pierce, |pos| {
+            destroyed[pos]
+        });
+        for pos in line {
+            if bomb_times[pos].is_none() || exploded[pos] {
+                blast[pos] = Some(blast[pos].map_or(time, |other: u32| other.min(time)));
+                if matches!(tiles[pos], TileType::BreakableWall(_)) {
+                    destroyed[pos] = true;
+                }
+                continue;
+            }
+
+            // A detonated bomb catches fire only when it explodes itself: in
+            // the same step if it's later in tile order, otherwise in the next
+            let chained = if (pos.x, pos.y) > (bomb_pos.x, bomb_pos.y) {
+                time
+            } else {
+                time + 1
+            };
+            bomb_times[pos] = bomb_times[pos].map(|other| other.min(chained));
+        }
+    }
+
+    blast
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::*;
+
+    #[test]
+    fn test_blast_times_match_simulation() {
+        let mut tiles = TileGrid::filled(11, 7, TileType::Empty);
+        for pos in tiles.pos_iter() {
+            if tiles.is_edge(pos) || (pos.x % 2 == 0 && pos.y % 2 == 0) {
+                tiles[pos] = TileType::SolidWall;
+            }
+        }
+        let bomb = |fuse, firepower| TileType::Bomb(Some(Bomb::new(fuse, firepower, 0)));
+        // A chain running backwards through the tile order, a wall that the
+        // first blast destroys and the second passes through, and a bomb that
+        // nothing reaches
+        tiles[IVec2::new(5, 1)] = bomb(30, 3);
+        tiles[IVec2::new(3, 1)] = bomb(90, 2);
+        tiles[IVec2::new(1, 3)] = bomb(60, 1);
+        tiles[IVec2::new(1, 2)] = TileType::BreakableWall(Box::new(TileType::Empty));
+        tiles[IVec2::new(7, 3)] = bomb(10, 2);
+        tiles[IVec2::new(7, 1)] = TileType::BreakableWall(Box::new(TileType::Empty));
+        // A blast that pierces walls
+        let mut pierce = Bomb::new(45, 3, 0);
+        pierce.pierce = true;
+        tiles[IVec2::new(5, 5)] = TileType::Bomb(Some(pierce));
+        for x in [3, 4, 7] {
+            tiles[IVec2::new(x, 5)] = TileType::BreakableWall(Box::new(TileType::Empty));
+        }
+
+        let predicted = blast_times(&tiles, 0);
+
+        let mut sim = GameSim::new(tiles.clone());
+        let mut actual = Grid::filled(tiles.width(), tiles.height(), None);
+        for step in 1..=BOMB_EXPLOSION_DELAY {
+            sim.step(&[]);
+            for pos in tiles.pos_iter() {
+                if matches!(sim.tiles()[pos], TileType::Explosion(_, _)) && actual[pos].is_none() {
+                    actual[pos] = Some(step);
+                }
+            }
+        }
+
+        for pos in tiles.pos_iter() {
+            assert_eq!(predicted[pos], actual[pos], "tile {}", pos);
+        }
+        assert_eq!(predicted[IVec2::new(1, 1)], Some(31));
+        assert_eq!(predicted[IVec2::new(8, 1)], Some(30));
+        assert_eq!(predicted[IVec2::new(1, 2)], Some(60));
+        assert_eq!(predicted[IVec2::new(2, 5)], Some(45));
+    }
+
+    #[test]
+    fn test_others_remote_bombs_are_imminent() {
+        let mut tiles = TileGrid::filled(5, 3, TileType::Empty);
+        let mut bomb = Bomb::new(90, 1, 0);
+        bomb.remote = Some(0);
+        tiles[IVec2::new(2, 1)] = TileType::Bomb(Some(bomb));
+
+        // Its owner decides when it goes off; anyone else has to expect it now
+        assert_eq!(blast_times(&tiles, 0)[IVec2::new(3, 1)], Some(90));
+        assert_eq!(blast_times(&tiles, 1)[IVec2::new(3, 1)], Some(1));
+    }
+}
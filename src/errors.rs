@@ -0,0 +1,6 @@
+use std::io::{Error, ErrorKind};
+
+// The error for files and packets that can't be made sense of
+pub(crate) fn invalid_data(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message.to_string())
+}
@@ -0,0 +1,352 @@
+use crate::basic::*;
+use crate::bot::*;
+use crate::danger::blast_times;
+use crate::errors::invalid_data;
+use crate::map::MapSeed;
+use crate::player::*;
+use crate::rules::Ruleset;
+use crate::sim::*;
+use crate::tile::*;
+use bevy::prelude::*;
+
+// An agent picks a direction to move in together with a button to press
+const MOTIONS: [Vec2; 5] = [Vec2::ZERO, Vec2::NEG_Y, Vec2::Y, Vec2::NEG_X, Vec2::X];
+const ACTIONS: [PlayerAction; 4] = [
+    PlayerAction::None,
+    PlayerAction::DropBomb,
+    PlayerAction::Throw,
+    PlayerAction::Detonate,
+];
+pub const NUM_ACTIONS: usize = MOTIONS.len() * ACTIONS.len();
+
+// Channels of an observation, each a grid the size of the map
+pub const SOLID_WALL_CHANNEL: usize = 0;
+pub const BREAKABLE_WALL_CHANNEL: usize = 1;
+// Fuse left as a fraction of the full fuse of the player who laid the bomb
+pub const BOMB_CHANNEL: usize = 2;
+// 1 for tiles a blast will reach now, falling to 0 for those it never reaches
+pub const DANGER_CHANNEL: usize = 3;
+pub const EXPLOSION_CHANNEL: usize = 4;
+pub const FIREPOWER_CHANNEL: usize = 5;
+pub const EXTRA_BOMB_CHANNEL: usize = 6;
+// Every power-up that changes more than a count, such as kick
+pub const OTHER_POWER_UP_CHANNEL: usize = 7;
+pub const SELF_CHANNEL: usize = 8;
+pub const OPPONENTS_CHANNEL: usize = 9;
+pub const NUM_CHANNELS: usize = 10;
+
+const PICKUP_REWARD: f32 = 0.1;
+const DEATH_REWARD: f32 = -1.0;
+const WIN_REWARD: f32 = 1.0;
+
+pub fn action_to_control(action: usize) -> PlayerControl {
+    PlayerControl {
+        motion: MOTIONS[action % MOTIONS.len()],
+        action: ACTIONS[action / MOTIONS.len() % ACTIONS.len()],
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct GymConfig {
+    // Players controlled through `step`, which take the first spawn points
+    pub num_agents: usize,
+    pub num_bots: usize,
+    pub bot_difficulty: BotDifficulty,
+    // Simulation ticks per call to `step`, holding the same motion
+    pub ticks_per_step: u32,
+    // An episode ends without a winner after this many ticks
+    pub max_ticks: u64,
+    // Decide the maps of episodes as well as how the game plays
+    pub rules: Ruleset,
+}
+
+impl Default for GymConfig {
+    fn default() -> Self {
+        Self {
+            num_agents: 1,
+            num_bots: 1,
+            bot_difficulty: BotDifficulty::Normal,
+            ticks_per_step: 4,
+            max_ticks: 180 * TICKS_PER_SECOND as u64,
+            rules: Ruleset::default(),
+        }
+    }
+}
+
+// What one agent sees: `grid[(channel * height + y) * width + x]`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Observation {
+    pub width: usize,
+    pub height: usize,
+    pub grid: Vec<f32>,
+    pub num_bombs: f32,
+    pub firepower: f32,
+    pub can_kick: bool,
+    pub can_throw: bool,
+    pub has_remote: bool,
+    pub has_pierce: bool,
+    pub has_line_bomb: bool,
+    pub curse: Option<Curse>,
+    pub shields: f32,
+    pub speed: f32,
+    pub alive: bool,
+}
+
+#[derive(Clone, Debug)]
+pub struct StepResult {
+    pub observations: Vec<Observation>,
+    pub rewards: Vec<f32>,
+    pub done: bool,
+}
+
+// A reinforcement learning environment around `GameSim`, in the style of a
+// gym. It runs without a window, as fast as the simulation steps.
+pub struct GymEnv {
+    config: GymConfig,
+    sim: GameSim,
+}
+
+impl GymEnv {
+    // Fails if the maps made by the rules can't seat every agent and bot. The
+    // environment starts out on an episode from seed 0.
+    pub fn new(config: GymConfig) -> std::io::Result<Self> {
+        let mut env = Self {
+            config,
+            sim: GameSim::default(),
+        };
+        env.reset(0)?;
+        Ok(env)
+    }
+
+    pub fn sim(&self) -> &GameSim {
+        &self.sim
+    }
+
+    // Starts an episode on the basic map made from `seed`
+    pub fn reset(&mut self, seed: u64) -> std::io::Result<Vec<Observation>> {
+        let rules = &self.config.rules;
+        let tiles = make_basic_map_with_rules(rules, &mut MapSeed(seed).rng());
+        let num_players = self.config.num_agents + self.config.num_bots;
+        if num_players > tiles.spawn_points().len() {
+            return Err(invalid_data(&format!(
+                "{} players don't fit on a map with {} spawn points",
+                num_players,
+                tiles.spawn_points().len()
+            )));
+        }
+        self.sim = GameSim::with_rules(tiles, rules.clone());
+        self.sim.set_seed(seed);
+        for agent in 0..self.config.num_agents {
+            self.sim.add_player(PlayerController::Agent(agent));
+        }
+        for _ in 0..self.config.num_bots {
+            self.sim
+                .add_player(PlayerController::Bot(self.config.bot_difficulty));
+        }
+        Ok(self.observations())
+    }
+
+    // Applies one action per agent, see `action_to_control`
+    pub fn step(&mut self, actions: &[usize]) -> StepResult {
+        let num_agents = self.config.num_agents;
+        let mut rewards = vec![0.0; num_agents];
+
+        for tick in 0..self.config.ticks_per_step {
+            if self.is_done() {
+                break;
+            }
+            let controls: Vec<_> = (0..self.sim.players().len())
+                .map(|player_index| match actions.get(player_index) {
+                    Some(action) if player_index < num_agents => {
+                        let mut control = action_to_control(*action);
+                        // A button is pressed once, not on every tick of the step
+                        if tick > 0 {
+                            control.action = PlayerAction::None;
+                        }
+                        control
+                    }
+                    _ => bot_control(&self.sim, player_index),
+                })
+                .collect();
+
+            for event in self.sim.step(&controls) {
+                match event {
+                    SimEvent::PowerUpCollected(player_index, _) if player_index < num_agents => {
+                        rewards[player_index] += PICKUP_REWARD;
+                    }
+                    SimEvent::PlayerDied(player_index) if player_index < num_agents => {
+                        rewards[player_index] += DEATH_REWARD;
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let done = self.is_done();
+        if let Some(Outcome::Win(winner)) = self.sim.outcome() {
+            if winner < num_agents && done {
+                rewards[winner] += WIN_REWARD;
+            }
+        }
+
+        StepResult {
+            observations: self.observations(),
+            rewards,
+            done,
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.sim.outcome().is_some()
+            || self.sim.tick() >= self.config.max_ticks
+            || self
+                .sim
+                .players()
+                .iter()
+                .take(self.config.num_agents)
+                .all(|player| !player.alive)
+    }
+
+    fn observations(&self) -> Vec<Observation> {
+        let tiles = self.sim.tiles();
+        let (width, height) = (tiles.width(), tiles.height());
+        let index = |channel: usize, pos: IVec2| {
+            (channel * height + pos.y as usize) * width + pos.x as usize
+        };
+
+        let players = self.sim.players();
+        let rules = self.sim.rules();
+        // Everything but the players is the same for every agent
+        let mut shared = vec![0.0; NUM_CHANNELS * width * height];
+        // Seen by nobody, so every remote bomb could go off at once
+        let blast = blast_times(tiles, usize::MAX);
+        for pos in tiles.pos_iter() {
+            let channel = match &tiles[pos] {
+                TileType::SolidWall => Some(SOLID_WALL_CHANNEL),
+                TileType::BreakableWall(_) => Some(BREAKABLE_WALL_CHANNEL),
+                TileType::Explosion(_, _) => Some(EXPLOSION_CHANNEL),
+                TileType::PowerUp(PowerUpType::Firepower) => Some(FIREPOWER_CHANNEL),
+                TileType::PowerUp(PowerUpType::ExtraBomb) => Some(EXTRA_BOMB_CHANNEL),
+                TileType::PowerUp(_) => Some(OTHER_POWER_UP_CHANNEL),
+                TileType::Bomb(Some(bomb)) => {
+                    let fuse = players
+                        .get(bomb.player_index)
+                        .map_or(rules.bomb_fuse, |player| player.bomb_fuse(rules));
+                    shared[index(BOMB_CHANNEL, pos)] = (bomb.fuse as f32 / fuse as f32).min(1.0);
+                    None
+                }
+                _ => None,
+            };
+            if let Some(channel) = channel {
+                shared[index(channel, pos)] = 1.0;
+            }
+            if let Some(time) = blast[pos] {
+                shared[index(DANGER_CHANNEL, pos)] =
+                    1.0 - (time as f32 / rules.bomb_fuse as f32).min(1.0);
+            }
+        }
+
+        (0..self.config.num_agents)
+            .map(|agent| {
+                let mut grid = shared.clone();
+                for player in players.iter().filter(|player| player.alive) {
+                    let Some(pos) = tiles.get_index_from_position(player.position) else {
+                        continue;
+                    };
+                    let channel = if player.player_index == agent {
+                        SELF_CHANNEL
+                    } else {
+                        OPPONENTS_CHANNEL
+                    };
+                    grid[index(channel, pos)] = 1.0;
+                }
+
+                let player = &players[agent];
+                Observation {
+                    width,
+                    height,
+                    grid,
+                    num_bombs: player.num_bombs as f32,
+                    firepower: player.firepower as f32,
+                    can_kick: player.can_kick,
+                    can_throw: player.can_throw,
+                    has_remote: player.has_remote,
+                    has_pierce: player.has_pierce,
+                    has_line_bomb: player.has_line_bomb,
+                    curse: player.curse.map(|active| active.curse),
+                    shields: player.shields as f32,
+                    speed: player.speed,
+                    alive: player.alive,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_episodes_are_reproducible() {
+        let config = GymConfig {
+            num_agents: 2,
+            num_bots: 1,
+            max_ticks: 30 * TICKS_PER_SECOND as u64,
+            ..default()
+        };
+        let mut envs = [
+            GymEnv::new(config.clone()).unwrap(),
+            GymEnv::new(config.clone()).unwrap(),
+        ];
+
+        let observations = envs[0].reset(9).unwrap();
+        assert_eq!(observations, envs[1].reset(9).unwrap());
+        assert_eq!(observations.len(), 2);
+        assert_eq!(
+            observations[0].grid.len(),
+            NUM_CHANNELS * MAP_DIMENSIONS.0 * MAP_DIMENSIONS.1
+        );
+        assert!(observations[0].grid.iter().sum::<f32>() > 0.0);
+
+        // Both agents wander and drop bombs until the episode ends
+        for step in 0.. {
+            let actions = [step % NUM_ACTIONS, (step * 7 + 3) % NUM_ACTIONS];
+            let results = envs.each_mut().map(|env| env.step(&actions));
+            assert_eq!(results[0].observations, results[1].observations);
+            assert_eq!(results[0].rewards, results[1].rewards);
+            if results[0].done {
+                break;
+            }
+        }
+        assert!(
+            envs[0].sim().tick() <= config.max_ticks + config.ticks_per_step as u64,
+            "episode should end by the time limit"
+        );
+    }
+
+    #[test]
+    fn test_players_must_fit_the_map() {
+        let config = GymConfig {
+            num_agents: 6,
+            num_bots: 3,
+            ..default()
+        };
+        assert!(GymEnv::new(config).is_err());
+
+        // A small map by the chosen rules is still played at its own size
+        let config = GymConfig {
+            num_agents: 2,
+            num_bots: 0,
+            rules: Ruleset {
+                map_width: 9,
+                map_height: 7,
+                ..Ruleset::chaos()
+            },
+            ..default()
+        };
+        let mut env = GymEnv::new(config).unwrap();
+        assert_eq!(env.step(&[0, 0]).observations[0].width, 9);
+        assert_eq!(env.sim().rules().name, "Chaos");
+    }
+}
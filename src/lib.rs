@@ -6,3 +6,17 @@ pub mod bevy_tree_query;
 pub mod models;
 pub mod sdf;
 pub mod tile_factory;
+pub mod sim;
+pub mod replay;
+pub mod netcode;
+pub mod server;
+pub mod lobby;
+pub mod checksum;
+pub mod bot;
+pub mod danger;
+pub mod nav;
+pub mod map_file;
+pub mod tournament;
+pub mod gym;
+pub mod rules;
+pub mod errors;
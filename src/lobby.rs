@@ -0,0 +1,636 @@
+use crate::basic::*;
+use crate::errors::invalid_data;
+use crate::map::MapSeed;
+use crate::models::MODEL_ANIMAL_PATH;
+use crate::netcode::RollbackSession;
+use crate::replay::ByteReader;
+use crate::rules::Ruleset;
+use crate::sim::GameSim;
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+const PACKET_MAGIC: &[u8; 4] = b"KCLB";
+
+// Hosts announce their lobbies to this port on every machine of the network
+pub const DISCOVERY_PORT: u16 = 7777;
+
+pub const MAX_LOBBY_PLAYERS: usize = MODEL_ANIMAL_PATH.len();
+
+const ANNOUNCE_INTERVAL: Duration = Duration::from_secs(1);
+const JOIN_INTERVAL: Duration = Duration::from_millis(500);
+// Lobbies and members that have been silent for this long are gone
+const LOBBY_TIMEOUT: Duration = Duration::from_secs(3);
+// The start message is sent again at this interval until it's acknowledged
+const START_INTERVAL: Duration = Duration::from_millis(200);
+// The start carries the host's rules, so it may need a whole datagram
+const MAX_START_LEN: usize = 65507;
+
+const ANNOUNCE: u8 = 0;
+const JOIN: u8 = 1;
+const WELCOME: u8 = 2;
+const LEAVE: u8 = 3;
+const START: u8 = 4;
+const START_ACK: u8 = 5;
+
+// A lobby seen on the network
+#[derive(Clone, Debug)]
+pub struct LobbyInfo {
+    pub address: SocketAddr,
+    pub name: String,
+    pub seed: u64,
+    pub map_width: usize,
+    pub map_height: usize,
+    pub num_players: usize,
+    pub max_players: usize,
+    last_seen: Instant,
+}
+
+impl LobbyInfo {
+    pub fn free_slots(&self) -> usize {
+        self.max_players.saturating_sub(self.num_players)
+    }
+}
+
+// Everything needed to begin an online match once a lobby starts
+pub struct MatchStart {
+    pub socket: UdpSocket,
+    pub local_player: usize,
+    // Address of every player in player order, including our own
+    pub addresses: Vec<SocketAddr>,
+    pub seed: u64,
+    // The host's rules, which every peer plays by
+    pub rules: Ruleset,
+}
+
+impl MatchStart {
+    // Every peer builds the same map from the shared seed and rules, and adds
+    // the players in lobby order, so each gets the spawn point of its slot
+    pub fn into_session(self) -> std::io::Result<RollbackSession> {
+        let tiles = make_basic_map_with_rules(&self.rules, &mut MapSeed(self.seed).rng());
+        let mut sim = GameSim::with_rules(tiles, self.rules);
+        sim.set_seed(self.seed);
+        RollbackSession::new(self.socket, self.local_player, self.addresses, sim)
+    }
+}
+
+struct LobbyMember {
+    address: SocketAddr,
+    last_heard: Instant,
+    // Whether they've acknowledged the start of the match
+    started: bool,
+}
+
+// A start message waiting to be acknowledged by every member
+struct PendingStart {
+    message: Vec<u8>,
+    addresses: Vec<SocketAddr>,
+    since: Instant,
+    last_sent: Instant,
+}
+
+// Announces a lobby and gathers the players who join it. The host is always
+// player 0 and the others take the next slots in the order they joined.
+pub struct LobbyHost {
+    socket: UdpSocket,
+    name: String,
+    seed: u64,
+    rules: Ruleset,
+    members: Vec<LobbyMember>,
+    last_announce: Option<Instant>,
+    announce_failed: bool,
+    pending_start: Option<PendingStart>,
+}
+
+impl LobbyHost {
+    pub fn new(
+        socket: UdpSocket,
+        name: String,
+        seed: u64,
+        rules: Ruleset,
+    ) -> std::io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        socket.set_broadcast(true)?;
+        Ok(Self {
+            socket,
+            name,
+            seed,
+            rules,
+            members: Vec::new(),
+            last_announce: None,
+            announce_failed: false,
+            pending_start: None,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn num_players(&self) -> usize {
+        1 + self.members.len()
+    }
+
+    pub fn is_starting(&self) -> bool {
+        self.pending_start.is_some()
+    }
+
+    // Keeps the lobby going, returning the match once every member has
+    // acknowledged its start
+    pub fn poll(&mut self) -> Option<MatchStart> {
+        let now = Instant::now();
+        if self.pending_start.is_some() {
+            return self.poll_start(now);
+        }
+        if self
+            .last_announce
+            .is_none_or(|last| now - last >= ANNOUNCE_INTERVAL)
+        {
+            self.announce();
+            self.last_announce = Some(now);
+        }
+
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    eprintln!("Unable to receive lobby messages: {}", err);
+                    break;
+                }
+            };
+
+            match read_header(&buf[..len]) {
+                Ok(JOIN) => self.join(from, now),
+                Ok(LEAVE) => self.members.retain(|member| member.address != from),
+                Ok(_) => {}
+                Err(err) => eprintln!("Ignoring packet from {}: {}", from, err),
+            }
+        }
+
+        self.members.retain(|member| {
+            let connected = now - member.last_heard < LOBBY_TIMEOUT;
+            if !connected {
+                println!("{} left the lobby", member.address);
+            }
+            connected
+        });
+        None
+    }
+
+    // Tell the members where everyone is. The match begins once they've all
+    // acknowledged it, see `poll`.
+    pub fn start(&mut self) {
+        // Members use the address they see our messages come from
+        let mut addresses = vec![SocketAddr::new(Ipv4Addr::UNSPECIFIED.into(), 0)];
+        addresses.extend(self.members.iter().map(|member| member.address));
+
+        let mut message = header(START);
+        message.extend_from_slice(&self.seed.to_le_bytes());
+        message.push(addresses.len() as u8);
+        for address in &addresses {
+            write_address(&mut message, address);
+        }
+        let rules = self.rules.to_ron();
+        message.extend_from_slice(&(rules.len() as u32).to_le_bytes());
+        message.extend_from_slice(rules.as_bytes());
+        let now = Instant::now();
+        self.pending_start = Some(PendingStart {
+            message,
+            addresses,
+            since: now,
+            last_sent: now,
+        });
+        self.send_start();
+    }
+
+    fn poll_start(&mut self, now: Instant) -> Option<MatchStart> {
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    eprintln!("Unable to receive lobby messages: {}", err);
+                    break;
+                }
+            };
+            let Some(member) = self.members.iter_mut().find(|m| m.address == from) else {
+                continue;
+            };
+            // Game packets mean they've started even if their acknowledgement
+            // was lost
+            if matches!(read_header(&buf[..len]), Ok(START_ACK) | Err(_)) {
+                member.started = true;
+            }
+        }
+
+        let pending = self.pending_start.as_mut()?;
+        let timed_out = now - pending.since >= LOBBY_TIMEOUT;
+        if !timed_out && self.members.iter().any(|member| !member.started) {
+            if now - pending.last_sent >= START_INTERVAL {
+                pending.last_sent = now;
+                self.send_start();
+            }
+            return None;
+        }
+
+        // Whoever hasn't answered by now will be dropped by the match itself
+        for member in self.members.iter().filter(|member| !member.started) {
+            println!("{} didn't acknowledge the start", member.address);
+        }
+        let pending = self.pending_start.take()?;
+        match self.socket.try_clone() {
+            Ok(socket) => Some(MatchStart {
+                socket,
+                local_player: 0,
+                addresses: pending.addresses,
+                seed: self.seed,
+                rules: self.rules.clone(),
+            }),
+            Err(err) => {
+                eprintln!("Unable to start the match: {}", err);
+                None
+            }
+        }
+    }
+
+    fn send_start(&self) {
+        let Some(pending) = &self.pending_start else {
+            return;
+        };
+        for member in self.members.iter().filter(|member| !member.started) {
+            if let Err(err) = self.socket.send_to(&pending.message, member.address) {
+                eprintln!("Unable to send to {}: {}", member.address, err);
+            }
+        }
+    }
+
+    fn join(&mut self, from: SocketAddr, now: Instant) {
+        let player_index = match self.members.iter().position(|m| m.address == from) {
+            Some(index) => index + 1,
+            None if self.num_players() < MAX_LOBBY_PLAYERS => {
+                println!("{} joined the lobby", from);
+                self.members.push(LobbyMember {
+                    address: from,
+                    last_heard: now,
+                    started: false,
+                });
+                self.members.len()
+            }
+            // No reply, so the lobby looks unresponsive to them
+            None => return,
+        };
+        self.members[player_index - 1].last_heard = now;
+
+        let mut bytes = header(WELCOME);
+        bytes.push(player_index as u8);
+        bytes.push(self.num_players() as u8);
+        if let Err(err) = self.socket.send_to(&bytes, from) {
+            eprintln!("Unable to send to {}: {}", from, err);
+        }
+    }
+
+    fn announcement(&self) -> Vec<u8> {
+        let mut bytes = header(ANNOUNCE);
+        let name = self.name.as_bytes();
+        let name = &name[..name.len().min(u8::MAX as usize)];
+        bytes.push(name.len() as u8);
+        bytes.extend_from_slice(name);
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.extend_from_slice(&(self.rules.map_width as u16).to_le_bytes());
+        bytes.extend_from_slice(&(self.rules.map_height as u16).to_le_bytes());
+        bytes.push(self.num_players() as u8);
+        bytes.push(MAX_LOBBY_PLAYERS as u8);
+        bytes
+    }
+
+    fn announce(&mut self) {
+        let bytes = self.announcement();
+        // Without a network, at least this machine can still see the lobby
+        let result = self
+            .socket
+            .send_to(&bytes, (Ipv4Addr::BROADCAST, DISCOVERY_PORT))
+            .or_else(|_| {
+                self.socket
+                    .send_to(&bytes, (Ipv4Addr::LOCALHOST, DISCOVERY_PORT))
+            });
+        if let (Err(err), false) = (result, self.announce_failed) {
+            eprintln!("Unable to announce the lobby: {}", err);
+            self.announce_failed = true;
+        }
+    }
+}
+
+// Lists the lobbies being announced on the network
+pub struct LobbyBrowser {
+    socket: UdpSocket,
+    lobbies: Vec<LobbyInfo>,
+}
+
+impl LobbyBrowser {
+    pub fn new() -> std::io::Result<Self> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, DISCOVERY_PORT))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            lobbies: Vec::new(),
+        })
+    }
+
+    pub fn lobbies(&self) -> &[LobbyInfo] {
+        &self.lobbies
+    }
+
+    pub fn poll(&mut self) {
+        let now = Instant::now();
+        let mut buf = [0u8; 512];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    eprintln!("Unable to receive lobby announcements: {}", err);
+                    break;
+                }
+            };
+
+            match read_announcement(&buf[..len], from, now) {
+                Ok(info) => match self.lobbies.iter_mut().find(|l| l.address == from) {
+                    Some(lobby) => *lobby = info,
+                    None => self.lobbies.push(info),
+                },
+                Err(err) => eprintln!("Ignoring packet from {}: {}", from, err),
+            }
+        }
+
+        self.lobbies
+            .retain(|lobby| now - lobby.last_seen < LOBBY_TIMEOUT);
+    }
+}
+
+// A player waiting in someone else's lobby
+pub struct LobbyClient {
+    socket: UdpSocket,
+    host: SocketAddr,
+    player_index: Option<usize>,
+    num_players: usize,
+    last_join: Option<Instant>,
+}
+
+impl LobbyClient {
+    pub fn new(host: SocketAddr) -> std::io::Result<Self> {
+        let any_ip: IpAddr = if host.is_ipv4() {
+            Ipv4Addr::UNSPECIFIED.into()
+        } else {
+            Ipv6Addr::UNSPECIFIED.into()
+        };
+        let socket = UdpSocket::bind((any_ip, 0))?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            host,
+            player_index: None,
+            num_players: 0,
+            last_join: None,
+        })
+    }
+
+    // Our slot in the lobby, once the host has accepted us
+    pub fn player_index(&self) -> Option<usize> {
+        self.player_index
+    }
+
+    pub fn num_players(&self) -> usize {
+        self.num_players
+    }
+
+    // Keeps our place in the lobby, returning the match once the host starts it
+    pub fn poll(&mut self) -> Option<MatchStart> {
+        let now = Instant::now();
+        if self
+            .last_join
+            .is_none_or(|last| now - last >= JOIN_INTERVAL)
+        {
+            self.send(JOIN);
+            self.last_join = Some(now);
+        }
+
+        let mut buf = vec![0u8; MAX_START_LEN];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    eprintln!("Unable to receive lobby messages: {}", err);
+                    break;
+                }
+            };
+            if from != self.host {
+                continue;
+            }
+
+            match self.read_message(&buf[..len]) {
+                Ok(Some(start)) => {
+                    // Otherwise the host keeps sending the start, which would
+                    // reach the match as stray packets
+                    self.send(START_ACK);
+                    return Some(start);
+                }
+                Ok(None) => {}
+                Err(err) => eprintln!("Ignoring packet from {}: {}", from, err),
+            }
+        }
+
+        None
+    }
+
+    pub fn leave(self) {
+        self.send(LEAVE);
+    }
+
+    fn send(&self, kind: u8) {
+        if let Err(err) = self.socket.send_to(&header(kind), self.host) {
+            eprintln!("Unable to send to {}: {}", self.host, err);
+        }
+    }
+
+    fn read_message(&mut self, bytes: &[u8]) -> std::io::Result<Option<MatchStart>> {
+        let mut reader = ByteReader::new(bytes);
+        match read_kind(&mut reader)? {
+            WELCOME => {
+                self.player_index = Some(reader.u8()? as usize);
+                self.num_players = reader.u8()? as usize;
+                Ok(None)
+            }
+            START => {
+                let Some(local_player) = self.player_index else {
+                    return Err(invalid_data("started before we joined"));
+                };
+                let seed = reader.u64()?;
+                let mut addresses = Vec::new();
+                for _ in 0..reader.u8()? {
+                    addresses.push(read_address(&mut reader)?);
+                }
+                // The host is player 0 and is wherever its messages come from
+                if let Some(host) = addresses.first_mut() {
+                    *host = self.host;
+                }
+                if local_player >= addresses.len() {
+                    return Err(invalid_data("we're missing from the match"));
+                }
+                let len = reader.u32()? as usize;
+                let rules = std::str::from_utf8(reader.take(len)?)
+                    .map_err(|_| invalid_data("ruleset is not text"))?;
+                Ok(Some(MatchStart {
+                    socket: self.socket.try_clone()?,
+                    local_player,
+                    addresses,
+                    seed,
+                    rules: Ruleset::from_ron(rules)?,
+                }))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+fn header(kind: u8) -> Vec<u8> {
+    let mut bytes = PACKET_MAGIC.to_vec();
+    bytes.push(kind);
+    bytes
+}
+
+fn read_kind(reader: &mut ByteReader) -> std::io::Result<u8> {
+    if reader.take(4)? != PACKET_MAGIC {
+        return Err(invalid_data("not a lobby packet"));
+    }
+    reader.u8()
+}
+
+fn read_header(bytes: &[u8]) -> std::io::Result<u8> {
+    read_kind(&mut ByteReader::new(bytes))
+}
+
+fn read_announcement(bytes: &[u8], from: SocketAddr, now: Instant) -> std::io::Result<LobbyInfo> {
+    let mut reader = ByteReader::new(bytes);
+    if read_kind(&mut reader)? != ANNOUNCE {
+        return Err(invalid_data("not a lobby announcement"));
+    }
+    let name_len = reader.u8()? as usize;
+    let name = String::from_utf8_lossy(reader.take(name_len)?).into_owned();
+
+    Ok(LobbyInfo {
+        address: from,
+        name,
+        seed: reader.u64()?,
+        map_width: reader.u16()? as usize,
+        map_height: reader.u16()? as usize,
+        num_players: reader.u8()? as usize,
+        max_players: reader.u8()? as usize,
+        last_seen: now,
+    })
+}
+
+fn write_address(bytes: &mut Vec<u8>, address: &SocketAddr) {
+    match address.ip() {
+        IpAddr::V4(ip) => {
+            bytes.push(4);
+            bytes.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            bytes.push(6);
+            bytes.extend_from_slice(&ip.octets());
+        }
+    }
+    bytes.extend_from_slice(&address.port().to_le_bytes());
+}
+
+fn read_address(reader: &mut ByteReader) -> std::io::Result<SocketAddr> {
+    let ip: IpAddr = match reader.u8()? {
+        4 => <[u8; 4]>::try_from(reader.take(4)?).unwrap().into(),
+        6 => <[u8; 16]>::try_from(reader.take(16)?).unwrap().into(),
+        tag => return Err(invalid_data(&format!("unknown address type {}", tag))),
+    };
+    Ok(SocketAddr::new(ip, reader.u16()?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lobby_join_and_start() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let host_address = socket.local_addr().unwrap();
+        let rules = Ruleset {
+            map_width: 15,
+            map_height: 11,
+            ..Ruleset::chaos()
+        };
+        let mut host = LobbyHost::new(socket, "Office".to_string(), 42, rules.clone()).unwrap();
+
+        let info = read_announcement(&host.announcement(), host_address, Instant::now()).unwrap();
+        assert_eq!(info.name, "Office");
+        assert_eq!((info.map_width, info.map_height), (15, 11));
+        assert_eq!(
+            (info.num_players, info.free_slots()),
+            (1, MAX_LOBBY_PLAYERS - 1)
+        );
+
+        let mut client = LobbyClient::new(host_address).unwrap();
+        let client_address = SocketAddr::new(
+            Ipv4Addr::LOCALHOST.into(),
+            client.socket.local_addr().unwrap().port(),
+        );
+        assert!(client.poll().is_none());
+        std::thread::sleep(Duration::from_millis(10));
+        host.poll();
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(client.poll().is_none());
+        assert_eq!(host.num_players(), 2);
+        assert_eq!(client.player_index(), Some(1));
+
+        // The host waits for the client to acknowledge the start
+        host.start();
+        assert!(host.poll().is_none());
+        std::thread::sleep(Duration::from_millis(10));
+        let client_start = client.poll().unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        let host_start = host.poll().unwrap();
+
+        assert_eq!(client_start.local_player, 1);
+        assert_eq!(client_start.seed, 42);
+        assert_eq!(client_start.addresses, vec![host_address, client_address]);
+        assert_eq!(host_start.addresses[1], client_address);
+
+        // Everyone plays by the host's rules
+        let session = client_start.into_session().unwrap();
+        assert_eq!(*session.sim().rules(), rules);
+        assert_eq!(session.sim().tiles().width(), 15);
+    }
+
+    #[test]
+    fn test_lobby_leave() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let host_address = socket.local_addr().unwrap();
+        let mut host =
+            LobbyHost::new(socket, "Office".to_string(), 42, Ruleset::default()).unwrap();
+
+        let mut client = LobbyClient::new(host_address).unwrap();
+        client.poll();
+        std::thread::sleep(Duration::from_millis(10));
+        host.poll();
+        assert_eq!(host.num_players(), 2);
+
+        client.leave();
+        std::thread::sleep(Duration::from_millis(10));
+        host.poll();
+        assert_eq!(host.num_players(), 1);
+    }
+}
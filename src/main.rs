@@ -1,30 +1,135 @@
 use bevy::{animation::animate_targets, pbr::CascadeShadowConfigBuilder, prelude::*};
 use killer_critters::{
-    basic::*, bevy_tree_query::*, map::*, models::*, player::*, sdf::*, tile::*, tile_factory::*,
+    basic::*, bevy_tree_query::*, bot::*, checksum::*, lobby::*, map::*, models::*, netcode::*,
+    player::*, replay::*, rules::*, server::*, sim::*, tile::*, tile_factory::*,
 };
-use std::{collections::HashMap, f32::consts::PI, path::PathBuf};
-use web_time::{Duration, Instant};
-
-const PER_FRAME_MOTION: f32 = TILE_SIZE / 20.0;
-const EXPLOSION_DURATION: Duration = Duration::from_millis(100);
-const BOMB_EXPLOSION_DELAY: Duration = Duration::from_secs(3);
-const FREE_SPACE_BORDER: f32 = 0.4;
+use std::{collections::BTreeMap, f32::consts::PI};
+use web_time::Duration;
 
 #[derive(States, Debug, Clone, Eq, PartialEq, Hash, Default)]
 enum GameState {
     #[default]
     Setup,
+    Lobby,
     Playing,
     GameOver,
 }
 
-const MAP_DIMENSIONS: (usize, usize) = (19, 13);
-
 #[derive(Resource)]
 struct AudioAssets {
     explosion_sound: Handle<AudioSource>,
 }
 
+// The latest control for every input device. Inputs are gathered every frame
+// but consumed on fixed simulation ticks, so actions are held until a tick
+// has used them.
+#[derive(Resource, Default)]
+struct PlayerInputs(BTreeMap<PlayerController, PlayerControl>);
+
+impl PlayerInputs {
+    fn set(&mut self, controller: PlayerController, control: PlayerControl) {
+        let entry = self.0.entry(controller).or_insert(control);
+        entry.motion = control.motion;
+        if control.action != PlayerAction::None {
+            entry.action = control.action;
+        }
+    }
+
+    fn clear_actions(&mut self) {
+        for control in self.0.values_mut() {
+            control.action = PlayerAction::None;
+        }
+    }
+
+    // The control of whichever local controller is in use, for games where
+    // this machine has only one player
+    fn any_control(&self) -> PlayerControl {
+        self.0
+            .values()
+            .find(|control| control.is_something())
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Component)]
+struct PausedText;
+
+// Options given on the command line
+#[derive(Resource, Default)]
+struct LaunchOptions {
+    // Play every round on the map made from this seed
+    seed: Option<u64>,
+    // Play back a recorded match instead of reading the controllers
+    replay: Option<Replay>,
+    // Gather players on the local network before an online match
+    lobby: Option<LobbyOptions>,
+    // Computer-controlled players added to local games
+    bots: usize,
+    bot_difficulty: BotDifficulty,
+}
+
+enum LobbyOptions {
+    Host(String),
+    Browse,
+}
+
+// Where we are in gathering the players of an online match
+#[derive(Resource)]
+enum LobbyState {
+    Hosting(Box<LobbyHost>),
+    Browsing(LobbyBrowser),
+    Joined(LobbyClient),
+}
+
+#[derive(Component)]
+struct LobbyText;
+
+#[derive(Resource)]
+struct OnlineSession(RollbackSession);
+
+// Connection to a dedicated server, which runs the game for us
+#[derive(Resource)]
+struct ServerConnection(ServerClient);
+
+// Whether this machine runs the game itself, rather than sharing it with peers
+// or following a server
+fn is_local_game(
+    session: Option<Res<OnlineSession>>,
+    connection: Option<Res<ServerConnection>>,
+) -> bool {
+    session.is_none() && connection.is_none()
+}
+
+fn is_replaying(options: Res<LaunchOptions>) -> bool {
+    options.replay.is_some()
+}
+
+// The inputs of the current match, saved when the match is over
+#[derive(Resource, Default)]
+struct ReplayRecorder(Replay);
+
+// Checks that the replay being played back matches what was recorded
+#[derive(Resource, Default)]
+struct ReplayVerification(ReplayChecker);
+
+// Links a player model to its player in the `GameSim`
+#[derive(Component)]
+struct PlayerModel {
+    player_index: usize,
+}
+
+// A bomb in the air, which isn't on any tile
+#[derive(Component)]
+struct ThrownBombModel;
+
+// The next wall to close in during sudden death, on its way down
+#[derive(Component)]
+struct FallingWallModel;
+
+#[derive(Component)]
+struct RoundClockText;
+
 // macos only
 #[cfg(target_os = "macos")]
 fn get_asset_path() -> String {
@@ -46,6 +151,14 @@ fn get_asset_path() -> String {
     "assets".to_string()
 }
 
+// The value following a cmdline arg, e.g. `--seed 42`
+#[cfg(not(target_arch = "wasm32"))]
+fn arg_value(name: &str) -> Option<String> {
+    let mut args = std::env::args().skip_while(|arg| arg != name);
+    args.next()?;
+    args.next()
+}
+
 fn main() {
     #[cfg(target_arch = "wasm32")]
     let window = Window {
@@ -56,14 +169,169 @@ fn main() {
     #[cfg(not(target_arch = "wasm32"))]
     let mut window = Window::default();
 
+    #[allow(unused_mut)]
+    let mut options = LaunchOptions::default();
+    #[allow(unused_mut)]
+    let mut rules = Ruleset::default();
+    #[allow(unused_mut)]
+    let mut connection = None;
+    #[allow(unused_mut)]
+    let mut online = None;
+
     #[cfg(not(target_arch = "wasm32"))]
     {
         // if cmdline arg --help, then print help
         if std::env::args().any(|arg| arg == "--help") {
-            println!("Usage: killer-critters [--fullscreen]");
+            println!(
+                "Usage: killer-critters [--fullscreen] [--seed <number>] [--replay <file>] \
+                 [--online <address>,<address>,... --player <index>] [--connect <address>] \
+                 [--host <name>] [--lobby] [--bots <count>] [--bot-difficulty easy|normal|hard] \
+                 [--rules {}|<file>]",
+                PRESET_NAMES.join("|")
+            );
             std::process::exit(0);
         }
 
+        // if cmdline arg --seed, then always play the map from that seed
+        if let Some(seed) = arg_value("--seed") {
+            match seed.parse() {
+                Ok(seed) => options.seed = Some(seed),
+                Err(_) => {
+                    eprintln!("Invalid seed `{}`", seed);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        // if cmdline arg --replay, then play back the recorded match
+        if let Some(path) = arg_value("--replay") {
+            match Replay::load(std::path::Path::new(&path)) {
+                Ok(replay) => {
+                    if replay.ticks_per_second != TICKS_PER_SECOND {
+                        eprintln!(
+                            "Replay was recorded at {} ticks per second, but the game runs at {}",
+                            replay.ticks_per_second, TICKS_PER_SECOND
+                        );
+                    }
+                    options.replay = Some(replay);
+                }
+                Err(err) => {
+                    eprintln!("Unable to load replay `{}`: {}", path, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        // if cmdline arg --bots, then fill that many seats with computer players
+        if let Some(bots) = arg_value("--bots") {
+            match bots.parse() {
+                Ok(bots) if bots < MODEL_ANIMAL_PATH.len() => options.bots = bots,
+                _ => {
+                    eprintln!(
+                        "Invalid bot count `{}`, expected 0 to {}",
+                        bots,
+                        MODEL_ANIMAL_PATH.len() - 1
+                    );
+                    std::process::exit(1);
+                }
+            }
+        }
+        if let Some(difficulty) = arg_value("--bot-difficulty") {
+            match BotDifficulty::from_name(&difficulty) {
+                Some(difficulty) => options.bot_difficulty = difficulty,
+                None => {
+                    eprintln!("Invalid bot difficulty `{}`", difficulty);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        // if cmdline arg --rules, then play by a preset or a ruleset file
+        if let Some(name) = arg_value("--rules") {
+            match Ruleset::from_preset_or_file(&name) {
+                Ok(loaded) => rules = loaded,
+                Err(err) => {
+                    eprintln!("Unable to load rules `{}`: {}", name, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        // if cmdline arg --online, then play over the network as player --player
+        if let Some(addresses) = arg_value("--online") {
+            let addresses: Result<Vec<std::net::SocketAddr>, _> = addresses
+                .split(',')
+                .map(|address| address.parse())
+                .collect();
+            let Ok(addresses) = addresses else {
+                eprintln!(
+                    "Invalid --online addresses, expected e.g. 127.0.0.1:7000,127.0.0.1:7001"
+                );
+                std::process::exit(1);
+            };
+            if addresses.len() < 2 || addresses.len() > MODEL_ANIMAL_PATH.len() {
+                eprintln!(
+                    "Online matches need between 2 and {} players",
+                    MODEL_ANIMAL_PATH.len()
+                );
+                std::process::exit(1);
+            }
+            let local_player = arg_value("--player").and_then(|index| index.parse().ok());
+            let Some(local_player) = local_player.filter(|index| *index < addresses.len()) else {
+                eprintln!("--online needs --player <index> of this peer's address");
+                std::process::exit(1);
+            };
+
+            // Every peer has to build the same map
+            let seed = options.seed.unwrap_or(0);
+            let address = addresses[local_player];
+            let session = std::net::UdpSocket::bind(address).and_then(|socket| {
+                MatchStart {
+                    socket,
+                    local_player,
+                    addresses,
+                    seed,
+                    rules: rules.clone(),
+                }
+                .into_session()
+            });
+            match session {
+                Ok(session) => online = Some((session, seed)),
+                Err(err) => {
+                    eprintln!("Unable to start online match on {}: {}", address, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+
+        // if cmdline arg --host, then open a lobby on the local network
+        if let Some(name) = arg_value("--host") {
+            options.lobby = Some(LobbyOptions::Host(name));
+        }
+
+        // if cmdline arg --lobby, then list the lobbies on the local network
+        if std::env::args().any(|arg| arg == "--lobby") {
+            options.lobby = Some(LobbyOptions::Browse);
+        }
+
+        // if cmdline arg --connect, then join the game on a dedicated server
+        if let Some(address) = arg_value("--connect") {
+            let Ok(address) = address.parse() else {
+                eprintln!(
+                    "Invalid server address `{}`, expected e.g. 192.168.1.10:7000",
+                    address
+                );
+                std::process::exit(1);
+            };
+            match ServerClient::connect(address, Duration::from_secs(5)) {
+                Ok(connected) => connection = Some(connected),
+                Err(err) => {
+                    eprintln!("Unable to connect to {}: {}", address, err);
+                    std::process::exit(1);
+                }
+            }
+        }
+
         // if cmdline arg --fullscreen, then set fullscreen
         if std::env::args().any(|arg| arg == "--fullscreen") {
             window.mode = bevy::window::WindowMode::BorderlessFullscreen(
@@ -72,47 +340,98 @@ fn main() {
         }
     }
 
-    App::new()
-        .insert_resource(AmbientLight {
-            color: Color::WHITE,
-            brightness: 2000.,
-        })
-        .add_plugins(
-            DefaultPlugins
-                .set(WindowPlugin {
-                    primary_window: Some(window),
-                    ..default()
-                })
-                .set(AssetPlugin {
-                    file_path: get_asset_path(),
-                    ..default()
-                }),
+    let mut app = App::new();
+    app.insert_resource(AmbientLight {
+        color: Color::WHITE,
+        brightness: 2000.,
+    })
+    .add_plugins(
+        DefaultPlugins
+            .set(WindowPlugin {
+                primary_window: Some(window),
+                ..default()
+            })
+            .set(AssetPlugin {
+                file_path: get_asset_path(),
+                ..default()
+            }),
+    )
+    .add_systems(Startup, setup_once)
+    .add_systems(OnEnter(GameState::Lobby), setup_lobby)
+    .add_systems(Update, update_lobby.run_if(in_state(GameState::Lobby)))
+    .add_systems(Last, leave_lobby_on_exit.run_if(in_state(GameState::Lobby)))
+    .add_systems(
+        OnEnter(GameState::Playing),
+        (setup_per_game, center_camera).chain(),
+    )
+    .insert_resource(options)
+    .insert_resource(rules)
+    .init_resource::<GameSim>()
+    .init_resource::<PlayerInputs>()
+    .init_resource::<MapSeed>()
+    .insert_resource(Time::<Fixed>::from_hz(TICKS_PER_SECOND as f64))
+    .add_systems(
+        FixedUpdate,
+        (
+            play_replay_inputs.run_if(is_replaying),
+            process_inputs.run_if(is_local_game),
+            process_online_inputs.run_if(resource_exists::<OnlineSession>),
         )
-        .add_systems(Startup, setup_once)
-        .add_systems(OnEnter(GameState::Playing), setup_per_game)
-        .add_systems(
-            Update,
+            .chain()
+            .run_if(in_state(GameState::Playing)),
+    )
+    // The server keeps playing while we show the game-over screen
+    .add_systems(
+        FixedUpdate,
+        process_server_messages.run_if(resource_exists::<ServerConnection>),
+    )
+    .add_systems(
+        Update,
+        (
+            setup_scene_once_loaded.before(animate_targets),
+            // Peers and servers can't wait for us while we're paused
+            toggle_pause.run_if(is_local_game),
             (
-                setup_scene_once_loaded.before(animate_targets),
-                keyboard_control,
-                gamepad_events,
+                (keyboard_control, gamepad_events).run_if(not(is_replaying)),
+                update_map_tiles,
                 update_tile_graphics,
-                map_transitions,
-                check_for_death,
+                update_bomb_positions,
+                update_thrown_bombs,
+                update_falling_wall,
+                update_round_clock,
+                update_player_models,
                 check_for_win,
-                check_pickup,
             )
-                .run_if(in_state(GameState::Playing)),
+                .chain(),
         )
-        .add_systems(OnEnter(GameState::GameOver), game_over)
-        .add_systems(Update, restart_game.run_if(in_state(GameState::GameOver)))
-        .init_state::<GameState>()
-        .run();
+            .run_if(in_state(GameState::Playing)),
+    )
+    .add_systems(OnEnter(GameState::GameOver), game_over)
+    .add_systems(
+        Update,
+        restart_game
+            .run_if(in_state(GameState::GameOver))
+            .run_if(not(resource_exists::<OnlineSession>)),
+    )
+    .init_state::<GameState>();
+
+    if let Some((connection, snapshot)) = connection {
+        app.insert_resource(ServerConnection(connection))
+            .insert_resource(MapSeed(snapshot.seed))
+            .insert_resource(snapshot.sim);
+    }
+    if let Some((session, seed)) = online {
+        app.insert_resource(OnlineSession(session))
+            .insert_resource(MapSeed(seed));
+    }
+
+    app.run();
 }
 
 fn setup_once(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
+    options: Res<LaunchOptions>,
     mut next_state: ResMut<NextState<GameState>>,
     mut meshes: ResMut<Assets<Mesh>>,
     mut materials: ResMut<Assets<StandardMaterial>>,
@@ -134,17 +453,13 @@ fn setup_once(
     commands.insert_resource(ResourceTileFactory::new(
         &mut animation_graphs,
         &asset_server,
+        &mut meshes,
+        &mut materials,
     ));
 
-    // Camera
-    let map_center = Vec3::new(
-        (MAP_DIMENSIONS.0 as f32 - 1.0) * TILE_SIZE / 2.0,
-        0.0,
-        (MAP_DIMENSIONS.1 as f32 - 1.0) * TILE_SIZE / 2.0,
-    );
+    // Camera, aimed at each map as it's set up by `center_camera`
     commands.spawn(Camera3dBundle {
-        transform: Transform::from_translation(map_center + Vec3::new(0.0, 20.0, 1.0))
-            .looking_at(map_center, Vec3::Y),
+        transform: camera_transform(MAP_DIMENSIONS.0, MAP_DIMENSIONS.1),
         ..default()
     });
 
@@ -171,32 +486,329 @@ fn setup_once(
         ..default()
     });
 
-    next_state.set(GameState::Playing);
+    if options.lobby.is_some() {
+        next_state.set(GameState::Lobby);
+    } else {
+        next_state.set(GameState::Playing);
+    }
+}
+
+fn setup_lobby(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    options: Res<LaunchOptions>,
+    rules: Res<Ruleset>,
+) {
+    let lobby = match &options.lobby {
+        Some(LobbyOptions::Host(name)) => {
+            std::net::UdpSocket::bind("0.0.0.0:0").and_then(|socket| {
+                let seed = options.seed.unwrap_or_else(|| MapSeed::random().0);
+                LobbyHost::new(socket, name.clone(), seed, rules.clone())
+                    .map(|host| LobbyState::Hosting(Box::new(host)))
+            })
+        }
+        Some(LobbyOptions::Browse) => LobbyBrowser::new().map(LobbyState::Browsing),
+        None => return,
+    };
+    match lobby {
+        Ok(lobby) => commands.insert_resource(lobby),
+        Err(err) => {
+            eprintln!("Unable to open the lobby: {}", err);
+            std::process::exit(1);
+        }
+    }
+
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font: asset_server.load("fonts/Handjet/Handjet-Medium.ttf"),
+                font_size: 40.0,
+                color: Color::WHITE,
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.0),
+            left: Val::Px(10.0),
+            ..default()
+        }),
+        LobbyText,
+    ));
+}
+
+// Hosts start the match with ENTER, and everyone else picks a lobby to join
+// with the number keys
+fn update_lobby(
+    mut commands: Commands,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut lobby: ResMut<LobbyState>,
+    mut text: Query<(Entity, &mut Text), With<LobbyText>>,
+    mut next_state: ResMut<NextState<GameState>>,
+) {
+    const LOBBY_KEYS: [KeyCode; 9] = [
+        KeyCode::Digit1,
+        KeyCode::Digit2,
+        KeyCode::Digit3,
+        KeyCode::Digit4,
+        KeyCode::Digit5,
+        KeyCode::Digit6,
+        KeyCode::Digit7,
+        KeyCode::Digit8,
+        KeyCode::Digit9,
+    ];
+
+    let mut started = None;
+    let message = match lobby.as_mut() {
+        LobbyState::Hosting(host) => {
+            started = host.poll();
+            if host.is_starting() {
+                "Starting the match...".to_string()
+            } else {
+                if keyboard_input.just_pressed(KeyCode::Enter) && host.num_players() > 1 {
+                    host.start();
+                }
+                format!(
+                    "Hosting `{}` (map seed {})\n{} players - press ENTER to start",
+                    host.name(),
+                    host.seed(),
+                    host.num_players()
+                )
+            }
+        }
+        LobbyState::Browsing(browser) => {
+            browser.poll();
+            let mut message = "Games on this network - press a number to join:".to_string();
+            for (i, info) in browser.lobbies().iter().enumerate().take(LOBBY_KEYS.len()) {
+                message += &format!(
+                    "\n{}: {} - map seed {}, {} free slots",
+                    i + 1,
+                    info.name,
+                    info.seed,
+                    info.free_slots()
+                );
+            }
+
+            let chosen = LOBBY_KEYS
+                .iter()
+                .position(|key| keyboard_input.just_pressed(*key))
+                .and_then(|i| browser.lobbies().get(i));
+            if let Some(info) = chosen.filter(|info| info.free_slots() > 0) {
+                match LobbyClient::new(info.address) {
+                    Ok(client) => *lobby = LobbyState::Joined(client),
+                    Err(err) => eprintln!("Unable to join {}: {}", info.address, err),
+                }
+            }
+            message
+        }
+        LobbyState::Joined(client) => {
+            started = client.poll();
+            match client.player_index() {
+                Some(player_index) => format!(
+                    "Joined as player {} of {} - waiting for the host to start, ESC to leave",
+                    player_index + 1,
+                    client.num_players()
+                ),
+                None => "Joining... - ESC to leave".to_string(),
+            }
+        }
+    };
+
+    // Give up our slot and go back to the list of lobbies
+    if started.is_none()
+        && keyboard_input.just_pressed(KeyCode::Escape)
+        && matches!(lobby.as_ref(), LobbyState::Joined(_))
+    {
+        match LobbyBrowser::new() {
+            Ok(browser) => {
+                if let LobbyState::Joined(client) =
+                    std::mem::replace(lobby.as_mut(), LobbyState::Browsing(browser))
+                {
+                    client.leave();
+                }
+            }
+            Err(err) => eprintln!("Unable to list the lobbies: {}", err),
+        }
+    }
+
+    for (_, mut text) in &mut text {
+        text.sections[0].value = message.clone();
+    }
+
+    let Some(start) = started else {
+        return;
+    };
+
+    // The lobby is over, and the match begins with the players it gathered
+    let seed = start.seed;
+    let rules = start.rules.clone();
+    match start.into_session() {
+        Ok(session) => {
+            commands.insert_resource(rules);
+            commands.remove_resource::<LobbyState>();
+            commands.insert_resource(OnlineSession(session));
+            commands.insert_resource(MapSeed(seed));
+            for (entity, _) in &text {
+                commands.entity(entity).despawn();
+            }
+            next_state.set(GameState::Playing);
+        }
+        Err(err) => eprintln!("Unable to start the match: {}", err),
+    }
+}
+
+// Let the host know we're gone when the window closes on the join screen
+fn leave_lobby_on_exit(world: &mut World) {
+    if world.resource::<Events<AppExit>>().is_empty() {
+        return;
+    }
+    if let Some(LobbyState::Joined(client)) = world.remove_resource::<LobbyState>() {
+        client.leave();
+    }
+}
+
+// Looking down on the middle of a map of the given size
+fn camera_transform(width: usize, height: usize) -> Transform {
+    let map_center = Vec3::new(
+        (width as f32 - 1.0) * TILE_SIZE / 2.0,
+        0.0,
+        (height as f32 - 1.0) * TILE_SIZE / 2.0,
+    );
+    Transform::from_translation(map_center + Vec3::new(0.0, 20.0, 1.0))
+        .looking_at(map_center, Vec3::Y)
 }
 
+// The rules, a replay or a server may have chosen a map of another size
+fn center_camera(sim: Res<GameSim>, mut cameras: Query<&mut Transform, With<Camera3d>>) {
+    for mut transform in &mut cameras {
+        *transform = camera_transform(sim.tiles().width(), sim.tiles().height());
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 fn setup_per_game(
     mut commands: Commands,
-    mut player_query: Query<(Entity, &mut Transform, &mut Player)>,
+    asset_server: Res<AssetServer>,
+    mut animation_graphs: ResMut<Assets<AnimationGraph>>,
+    options: Res<LaunchOptions>,
+    rules: Res<Ruleset>,
+    mut map_seed: ResMut<MapSeed>,
+    mut sim: ResMut<GameSim>,
+    session: Option<Res<OnlineSession>>,
+    connection: Option<Res<ServerConnection>>,
 ) {
-    // Recreate the map
-    let map_component = make_basic_map(&mut commands, MAP_DIMENSIONS.0, MAP_DIMENSIONS.1);
-    let starting_positions = map_component.spawn_points().to_vec();
+    commands.spawn((
+        TextBundle::from_section(
+            "",
+            TextStyle {
+                font: asset_server.load("fonts/Handjet/Handjet-Medium.ttf"),
+                font_size: 40.0,
+                color: Color::WHITE,
+            },
+        )
+        .with_style(Style {
+            position_type: PositionType::Absolute,
+            top: Val::Px(10.0),
+            right: Val::Px(10.0),
+            ..default()
+        }),
+        RoundClockText,
+    ));
+
+    // The peers have agreed on the map and the players of an online match
+    if let Some(session) = session {
+        *sim = session.0.sim().clone();
+        spawn_map(&mut commands, sim.tiles());
+        for player_index in 0..sim.players().len() {
+            spawn_player_model(
+                &mut commands,
+                &asset_server,
+                &mut animation_graphs,
+                &sim,
+                player_index,
+            );
+        }
+        return;
+    }
+
+    // The server has already chosen the map and the players
+    if connection.is_some() {
+        spawn_map(&mut commands, sim.tiles());
+        return;
+    }
+
+    let mut rules = rules.clone();
+    *map_seed = options.seed.map_or_else(MapSeed::random, MapSeed);
+    if let Some(replay) = &options.replay {
+        // A replay is played back by the rules it was recorded with
+        *map_seed = MapSeed(replay.seed);
+        rules = replay.rules.clone();
+    }
+
+    // Recreate the map, resetting the players onto their spawn points
+    sim.set_rules(rules.clone());
+    sim.set_seed(map_seed.0);
+    sim.restart(make_basic_map_with_rules(&rules, &mut map_seed.rng()));
+    spawn_map(&mut commands, sim.tiles());
+
+    if let Some(replay) = &options.replay {
+        // Start with the same players that the recording started with
+        if sim.players().is_empty() {
+            for controller in &replay.controllers {
+                if let Some(player_index) = sim.add_player(*controller) {
+                    spawn_player_model(
+                        &mut commands,
+                        &asset_server,
+                        &mut animation_graphs,
+                        &sim,
+                        player_index,
+                    );
+                }
+            }
+        }
+        if !replay.has_checksums() {
+            println!("This replay has no recorded checksums, so desyncs can't be detected");
+        }
+        commands.insert_resource(ReplayVerification::default());
+    } else {
+        // Bots join the first round; after that they stay like everyone else
+        if sim.players().is_empty() {
+            for _ in 0..options.bots {
+                let controller = PlayerController::Bot(options.bot_difficulty);
+                if let Some(player_index) = sim.add_player(controller) {
+                    spawn_player_model(
+                        &mut commands,
+                        &asset_server,
+                        &mut animation_graphs,
+                        &sim,
+                        player_index,
+                    );
+                }
+            }
+        }
+
+        let controllers = sim
+            .players()
+            .iter()
+            .map(|player| player.controller)
+            .collect();
+        commands.insert_resource(ReplayRecorder(Replay::new(
+            map_seed.0,
+            TICKS_PER_SECOND,
+            rules,
+            controllers,
+        )));
+    }
+}
+
+fn spawn_map(commands: &mut Commands, tiles: &TileGrid) {
+    let map_component = Map::from_tile_grid(commands, tiles);
 
     commands.spawn((
         map_component,
         Transform::from_xyz(0.0, 0.0, 0.0),
         GlobalTransform::default(),
     ));
-
-    // Reset character positions
-    for (entity, mut transform, mut player) in &mut player_query {
-        let starting_position = starting_positions[player.player_index];
-        transform.translation =
-            Vec3::new(starting_position.x as f32, 0.0, starting_position.y as f32);
-        commands.entity(entity).insert(Alive {});
-        commands.entity(entity).insert(Visibility::Visible);
-        player.reset();
-    }
 }
 
 // An `AnimationPlayer` is automatically added to the scene when it's ready.
@@ -252,119 +864,82 @@ fn vec3_xz(v: Vec2) -> Vec3 {
     Vec3::new(v.x, 0.0, v.y)
 }
 
-fn control_player(
-    control: &PlayerControl,
-    player: (Entity, &mut Transform, &mut Player),
-    query_children: &Query<&Children>,
-    maps: &Query<(&Transform, &Map), Without<Player>>,
-    tiles: &mut ParamSet<(Query<&Tile>, Query<&mut Tile>)>,
-    query_transitions: &mut Query<(&mut AnimationTransitions, &mut AnimationPlayer)>,
+fn spawn_player_model(
+    commands: &mut Commands,
+    asset_server: &AssetServer,
+    animation_graphs: &mut Assets<AnimationGraph>,
+    sim: &GameSim,
+    player_index: usize,
 ) {
-    let (player_entity, parent_from_frame, player) = player;
-
-    let cur_pos_in_world = parent_from_frame.translation.xz();
-    let mut new_pos_in_world = cur_pos_in_world + PER_FRAME_MOTION * control.motion;
-
-    for (map_transform, map) in maps {
-        let cur_pos_in_map = cur_pos_in_world - map_transform.translation.xz();
-        let new_pos_in_map = new_pos_in_world - map_transform.translation.xz();
-
-        let icur_pos_in_map = map.get_index_from_position(cur_pos_in_map);
-        let inew_pos_in_map =
-            map.get_index_from_position(new_pos_in_world - map_transform.translation.xz());
+    commands.spawn((
+        SceneBundle {
+            scene: asset_server
+                .load(GltfAssetLabel::Scene(0).from_asset(MODEL_ANIMAL_PATH[player_index])),
+            transform: Transform::from_translation(vec3_xz(sim.players()[player_index].position)),
+            ..default()
+        },
+        PlayerModel { player_index },
+        animation_graphs.add(AnimalAnimation::load_graph(
+            asset_server,
+            MODEL_ANIMAL_PATH[player_index],
+        )),
+    ));
+}
 
-        if let (Some(icur_pos_in_map), Some(mut inew_pos_in_map)) =
-            (icur_pos_in_map, inew_pos_in_map)
-        {
-            let cur_tile_is_bomb = matches!(
-                tiles.p0().get(map[icur_pos_in_map]).unwrap().tile_type,
-                TileType::Bomb(_)
-            );
+// Replace the controllers' inputs with the recorded ones for this tick
+fn play_replay_inputs(
+    options: Res<LaunchOptions>,
+    sim: Res<GameSim>,
+    mut inputs: ResMut<PlayerInputs>,
+    mut next_state: ResMut<NextState<GameState>>,
+    verification: Option<ResMut<ReplayVerification>>,
+) {
+    let Some(replay) = &options.replay else {
+        return;
+    };
 
-            let new_sdf = map_sdf(
-                map,
-                new_pos_in_map,
-                &tiles.p0(),
-                if cur_tile_is_bomb {
-                    Some(icur_pos_in_map)
-                } else {
-                    None
-                },
+    if let Some(mut verification) = verification {
+        if let Some(difference) = verification.0.check(replay, &StateDigest::of(&sim)) {
+            eprintln!(
+                "Replay desynced at tick {}: first difference is {}",
+                sim.tick(),
+                difference
             );
-
-            if -FREE_SPACE_BORDER < new_sdf.0 && new_sdf.0 < 0.0 {
-                new_pos_in_world = cur_pos_in_world + new_sdf.1 * PER_FRAME_MOTION;
-                inew_pos_in_map = map
-                    .get_index_from_position(new_pos_in_world - map_transform.translation.xz())
-                    .unwrap();
-            }
-
-            let tile_entity = map[inew_pos_in_map];
-            if let Ok(mut tile) = tiles.p1().get_mut(tile_entity) {
-                if control.action == PlayerAction::DropBomb && tile.tile_type == TileType::Empty {
-                    if player.num_bombs > 0 {
-                        tile.tile_type = TileType::Bomb(Some(Bomb {
-                            when_to_explode: Instant::now() + BOMB_EXPLOSION_DELAY,
-                            firepower: player.firepower,
-                            player_entity: player_entity,
-                        }));
-                        tile.set_changed();
-                        player.num_bombs -= 1;
-                    }
-                }
-            }
         }
     }
 
-    parent_from_frame.translation = vec3_xz(new_pos_in_world);
-
-    let mut target_animation = AnimalAnimation::Idle.to_node_index();
-
-    if control.motion.length() > 0.0 {
-        let dir2d = control.motion.normalize();
-        let roty = Quat::from_rotation_y(dir2d.x.atan2(dir2d.y));
-        parent_from_frame.rotation = roty;
-
-        target_animation = AnimalAnimation::Run.to_node_index();
+    match replay.inputs_at(sim.tick()) {
+        Some(recorded) => inputs.0 = recorded.clone(),
+        None => {
+            // The recording has ended without a winner
+            inputs.0.clear();
+            next_state.set(GameState::GameOver);
+        }
     }
-
-    play_animation(
-        player_entity,
-        target_animation,
-        Duration::from_millis(400),
-        true,
-        query_children,
-        query_transitions,
-    );
 }
 
 fn process_inputs(
-    commands: &mut Commands,
-    asset_server: &Res<AssetServer>,
-    animation_graphs: &mut ResMut<Assets<AnimationGraph>>,
-    inputs: &HashMap<PlayerController, PlayerControl>,
-    maps: &Query<(&Transform, &Map), Without<Player>>,
-    query_children: &Query<&Children>,
-    mut tiles: &mut ParamSet<(Query<&Tile>, Query<&mut Tile>)>,
-    query_player: &mut Query<(Entity, &mut Transform, &mut Player), Without<Map>>,
-    alive_query: &Query<(), With<Alive>>,
-    mut query_transitions: &mut Query<(&mut AnimationTransitions, &mut AnimationPlayer)>,
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    audio_assets: Res<AudioAssets>,
+    mut animation_graphs: ResMut<Assets<AnimationGraph>>,
+    mut inputs: ResMut<PlayerInputs>,
+    mut sim: ResMut<GameSim>,
+    recorder: Option<ResMut<ReplayRecorder>>,
 ) {
-    'outer: for (controller, control) in inputs {
-        for (entity, mut transform, mut player) in query_player.iter_mut() {
-            if player.controller == *controller {
-                if alive_query.get(entity).is_ok() {
-                    control_player(
-                        control,
-                        (entity, &mut transform, &mut player),
-                        &query_children,
-                        &maps,
-                        &mut tiles,
-                        &mut query_transitions,
-                    );
-                }
-                continue 'outer;
-            }
+    if let Some(mut recorder) = recorder {
+        recorder.0.record_tick(&inputs.0, &StateDigest::of(&sim));
+    }
+
+    // Bots decide from the game state alone, so they aren't recorded
+    let mut controls: Vec<_> = (0..sim.players().len())
+        .map(|player_index| bot_control(&sim, player_index))
+        .collect();
+
+    for (controller, control) in &inputs.0 {
+        if let Some(player_index) = sim.player_for_controller(*controller) {
+            controls[player_index] = *control;
+            continue;
         }
 
         if !control.is_something() {
@@ -373,64 +948,108 @@ fn process_inputs(
         }
 
         // unhandled input - let's create a player
-        let player_index = query_player.iter().count();
-        if player_index >= MODEL_ANIMAL_PATH.len() {
+        if sim.players().len() >= MODEL_ANIMAL_PATH.len() {
             continue;
         }
+        let Some(player_index) = sim.add_player(*controller) else {
+            continue;
+        };
+        spawn_player_model(
+            &mut commands,
+            &asset_server,
+            &mut animation_graphs,
+            &sim,
+            player_index,
+        );
 
-        let mut transform = Transform::default();
+        // only add one player per iteration
+        break;
+    }
 
-        // lookup spawn point
-        if let Ok((_, map)) = maps.get_single() {
-            if player_index >= map.spawn_points().len() {
-                eprintln!("No spawn point for player {}", player_index);
-                continue;
-            }
-            let starting_position = map.spawn_points()[player_index];
-            transform = Transform::from_translation(vec3_xz(Vec2::new(
-                starting_position.x as f32,
-                starting_position.y as f32,
-            )));
-        }
+    inputs.clear_actions();
 
-        commands.spawn((
-            SceneBundle {
-                scene: asset_server
-                    .load(GltfAssetLabel::Scene(0).from_asset(MODEL_ANIMAL_PATH[player_index])),
-                transform,
-                ..default()
-            },
-            Player::new(player_index, *controller),
-            animation_graphs.add(AnimalAnimation::load_graph(
-                &asset_server,
-                MODEL_ANIMAL_PATH[player_index],
-            )),
-            Alive {},
-        ));
+    let events = sim.step(&controls);
+    play_event_sounds(&mut commands, &audio_assets, &events);
+}
 
-        // only add one player per iteration
-        return;
+// Any local controller drives our player, and the session steps its own copy
+// of the simulation which the view then follows
+fn process_online_inputs(
+    mut commands: Commands,
+    audio_assets: Res<AudioAssets>,
+    mut inputs: ResMut<PlayerInputs>,
+    mut session: ResMut<OnlineSession>,
+    mut sim: ResMut<GameSim>,
+) {
+    // Keep the actions while waiting for the other peers
+    if let Some(events) = session.0.advance(inputs.any_control()) {
+        inputs.clear_actions();
+        play_event_sounds(&mut commands, &audio_assets, &events);
     }
+
+    *sim = session.0.sim().clone();
 }
 
-fn keyboard_control(
+// Send our control to the server and show the latest state it sent back
+#[allow(clippy::too_many_arguments)]
+fn process_server_messages(
     mut commands: Commands,
     asset_server: Res<AssetServer>,
+    audio_assets: Res<AudioAssets>,
     mut animation_graphs: ResMut<Assets<AnimationGraph>>,
-    keyboard_input: Res<ButtonInput<KeyCode>>,
-    maps: Query<(&Transform, &Map), Without<Player>>,
-    query_children: Query<&Children>,
-    mut tiles: ParamSet<(Query<&Tile>, Query<&mut Tile>)>,
-    mut query_player: Query<(Entity, &mut Transform, &mut Player), Without<Map>>,
-    alive_query: Query<(), With<Alive>>,
-    mut query_transitions: Query<(&mut AnimationTransitions, &mut AnimationPlayer)>,
+    mut inputs: ResMut<PlayerInputs>,
+    mut connection: ResMut<ServerConnection>,
+    mut sim: ResMut<GameSim>,
+    mut map_seed: ResMut<MapSeed>,
+    models: Query<&PlayerModel>,
 ) {
+    if let Err(err) = connection.0.send_control(inputs.any_control()) {
+        eprintln!("Unable to send to the server: {}", err);
+    }
+    inputs.clear_actions();
+
+    let Some(snapshot) = connection.0.receive() else {
+        return;
+    };
+    *sim = snapshot.sim;
+    if map_seed.0 != snapshot.seed {
+        *map_seed = MapSeed(snapshot.seed);
+    }
+    play_event_sounds(&mut commands, &audio_assets, &snapshot.events);
+
+    // Show the players that have joined since the last snapshot
+    for player_index in models.iter().count()..sim.players().len() {
+        spawn_player_model(
+            &mut commands,
+            &asset_server,
+            &mut animation_graphs,
+            &sim,
+            player_index,
+        );
+    }
+}
+
+fn play_event_sounds(commands: &mut Commands, audio_assets: &AudioAssets, events: &[SimEvent]) {
+    for event in events {
+        if let SimEvent::BombExploded(_) = event {
+            commands.spawn((AudioBundle {
+                source: audio_assets.explosion_sound.clone(),
+                settings: PlaybackSettings::DESPAWN,
+                ..default()
+            },));
+        }
+    }
+}
+
+fn keyboard_control(keyboard_input: Res<ButtonInput<KeyCode>>, mut inputs: ResMut<PlayerInputs>) {
     struct KeyMap {
         up: KeyCode,
         down: KeyCode,
         left: KeyCode,
         right: KeyCode,
         action: KeyCode,
+        throw: KeyCode,
+        detonate: KeyCode,
     }
 
     const PLAYER_KEYS: [(PlayerController, KeyMap); 2] = [
@@ -442,6 +1061,8 @@ fn keyboard_control(
                 left: KeyCode::ArrowLeft,
                 right: KeyCode::ArrowRight,
                 action: KeyCode::Space,
+                throw: KeyCode::Enter,
+                detonate: KeyCode::ShiftRight,
             },
         ),
         (
@@ -452,12 +1073,12 @@ fn keyboard_control(
                 left: KeyCode::KeyA,
                 right: KeyCode::KeyD,
                 action: KeyCode::KeyQ,
+                throw: KeyCode::KeyE,
+                detonate: KeyCode::KeyR,
             },
         ),
     ];
 
-    let mut inputs = HashMap::new();
-
     for (controller, key_map) in PLAYER_KEYS {
         let mut control = PlayerControl {
             motion: Vec2::ZERO,
@@ -479,45 +1100,26 @@ fn keyboard_control(
 
         if keyboard_input.just_pressed(key_map.action) {
             control.action = PlayerAction::DropBomb;
+        } else if keyboard_input.just_pressed(key_map.throw) {
+            control.action = PlayerAction::Throw;
+        } else if keyboard_input.just_pressed(key_map.detonate) {
+            control.action = PlayerAction::Detonate;
         }
 
         if control.motion.length() > 0.0 {
             control.motion = control.motion.normalize();
         }
 
-        inputs.insert(controller, control);
+        inputs.set(controller, control);
     }
-
-    process_inputs(
-        &mut commands,
-        &asset_server,
-        &mut animation_graphs,
-        &inputs,
-        &maps,
-        &query_children,
-        &mut tiles,
-        &mut query_player,
-        &alive_query,
-        &mut query_transitions,
-    );
 }
 
 fn gamepad_events(
-    mut commands: Commands,
-    asset_server: Res<AssetServer>,
-    mut animation_graphs: ResMut<Assets<AnimationGraph>>,
     gamepads: Res<Gamepads>,
     axes: Res<Axis<GamepadAxis>>,
     buttons: Res<ButtonInput<GamepadButton>>,
-    maps: Query<(&Transform, &Map), Without<Player>>,
-    query_children: Query<&Children>,
-    mut tiles: ParamSet<(Query<&Tile>, Query<&mut Tile>)>,
-    mut query_player: Query<(Entity, &mut Transform, &mut Player), Without<Map>>,
-    alive_query: Query<(), With<Alive>>,
-    mut query_transitions: Query<(&mut AnimationTransitions, &mut AnimationPlayer)>,
+    mut inputs: ResMut<PlayerInputs>,
 ) {
-    let mut inputs = HashMap::new();
-
     for gamepad in gamepads.iter() {
         let mut control = PlayerControl {
             motion: Vec2::ZERO,
@@ -536,33 +1138,125 @@ fn gamepad_events(
         // Process buttons
         if buttons.just_pressed(GamepadButton::new(gamepad, GamepadButtonType::South)) {
             control.action = PlayerAction::DropBomb;
+        } else if buttons.just_pressed(GamepadButton::new(gamepad, GamepadButtonType::East)) {
+            control.action = PlayerAction::Throw;
+        } else if buttons.just_pressed(GamepadButton::new(gamepad, GamepadButtonType::West)) {
+            control.action = PlayerAction::Detonate;
         }
 
-        inputs.insert(PlayerController::Gamepad(gamepad.id), control);
+        inputs.set(PlayerController::Gamepad(gamepad.id), control);
     }
+}
 
-    process_inputs(
-        &mut commands,
-        &asset_server,
-        &mut animation_graphs,
-        &inputs,
-        &maps,
-        &query_children,
-        &mut tiles,
-        &mut query_player,
-        &alive_query,
-        &mut query_transitions,
-    );
+// Pausing virtual time stops the fixed simulation ticks, and with them every
+// bomb and explosion timer. The game also pauses when the window loses focus.
+fn toggle_pause(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    keyboard_input: Res<ButtonInput<KeyCode>>,
+    mut focus_events: EventReader<bevy::window::WindowFocused>,
+    mut time: ResMut<Time<Virtual>>,
+    paused_text: Query<Entity, With<PausedText>>,
+) {
+    let lost_focus = focus_events.read().any(|event| !event.focused);
+    let toggle = keyboard_input.just_pressed(KeyCode::KeyP);
+
+    if time.is_paused() && toggle {
+        time.unpause();
+        for entity in &paused_text {
+            commands.entity(entity).despawn();
+        }
+    } else if !time.is_paused() && (toggle || lost_focus) {
+        time.pause();
+        commands.spawn((
+            TextBundle::from_section(
+                "Paused - press P to resume",
+                TextStyle {
+                    font: asset_server.load("fonts/Handjet/Handjet-Medium.ttf"),
+                    font_size: 40.0,
+                    color: Color::WHITE,
+                },
+            )
+            .with_style(Style {
+                position_type: PositionType::Absolute,
+                top: Val::Px(10.0),
+                left: Val::Px(10.0),
+                ..default()
+            }),
+            PausedText,
+        ));
+    }
+}
+
+// Copy the simulation's tiles onto the tile entities, touching only those
+// that changed so that `update_tile_graphics` sees just the differences
+fn update_map_tiles(sim: Res<GameSim>, maps: Query<&Map>, mut tiles: Query<&mut Tile>) {
+    for map in &maps {
+        for pos in map.pos_iter() {
+            if let Ok(mut tile) = tiles.get_mut(map[pos]) {
+                if tile.tile_type != sim.tiles()[pos] {
+                    tile.tile_type = sim.tiles()[pos].clone();
+                }
+            }
+        }
+    }
+}
+
+// Invulnerable players blink, switching every this many ticks
+const FLICKER_TICKS: u64 = 4;
+
+fn update_player_models(
+    sim: Res<GameSim>,
+    mut models: Query<(Entity, &PlayerModel, &mut Transform, &mut Visibility)>,
+    query_children: Query<&Children>,
+    mut query_transitions: Query<(&mut AnimationTransitions, &mut AnimationPlayer)>,
+) {
+    for (entity, model, mut transform, mut visibility) in &mut models {
+        let Some(player) = sim.players().get(model.player_index) else {
+            continue;
+        };
+
+        if !player.alive {
+            *visibility = Visibility::Hidden;
+            continue;
+        }
+        *visibility = if player.invulnerable > 0 && (sim.tick() / FLICKER_TICKS) & 1 == 0 {
+            Visibility::Hidden
+        } else {
+            Visibility::Visible
+        };
+
+        transform.translation = vec3_xz(player.position);
+        transform.rotation = Quat::from_rotation_y(player.facing.x.atan2(player.facing.y));
+
+        let target_animation = if player.is_moving {
+            AnimalAnimation::Run.to_node_index()
+        } else {
+            AnimalAnimation::Idle.to_node_index()
+        };
+
+        play_animation(
+            entity,
+            target_animation,
+            Duration::from_millis(400),
+            true,
+            &query_children,
+            &mut query_transitions,
+        );
+    }
 }
 
 fn update_tile_graphics(
     mut commands: Commands,
     mut query_tiles: Query<(Entity, &Tile, &Transform), Changed<Tile>>,
     game_assets: Res<ResourceTileFactory>,
+    map_seed: Res<MapSeed>,
 ) {
     for (entity, tile, transform) in &mut query_tiles {
         // save the transform
         let transform = transform.clone();
+        let mut rng =
+            map_seed.tile_rng((transform.translation.xz() / TILE_SIZE).round().as_ivec2());
 
         // Removing PbrBundle and SceneBundle
         commands.entity(entity).remove::<PbrBundle>();
@@ -570,7 +1264,7 @@ fn update_tile_graphics(
 
         // Insert the correct bundle and replace the transform that was removed
         // when we removed the PbrBundle and SceneBundle
-        match game_assets.make_tile(&tile.tile_type) {
+        match game_assets.make_tile(&tile.tile_type, &mut rng) {
             GameAsset::None => {}
             GameAsset::Scene(scene) => {
                 commands.entity(entity).insert(scene.clone());
@@ -578,11 +1272,14 @@ fn update_tile_graphics(
             GameAsset::AnimatedScene(scene) => {
                 commands.entity(entity).insert(scene.clone());
             }
+            GameAsset::Mesh(mesh) => {
+                commands.entity(entity).insert(mesh.clone());
+            }
         };
         let transform = Transform::from_translation(transform.translation);
 
         if let TileType::BreakableWall(_) = tile.tile_type {
-            let r = random_orthogonal_rotation();
+            let r = random_orthogonal_rotation(&mut rng);
             commands.entity(entity).insert(transform.with_rotation(r));
         } else {
             commands.entity(entity).insert(transform);
@@ -590,121 +1287,155 @@ fn update_tile_graphics(
     }
 }
 
-fn map_transitions(
-    mut commands: Commands,
-    audio_assets: Res<AudioAssets>,
-    mut maps: Query<&Map>,
-    mut tiles: Query<&mut Tile>,
-    mut query_player: Query<&mut Player>,
+// Draw kicked bombs between the tiles they are sliding across
+fn update_bomb_positions(
+    sim: Res<GameSim>,
+    maps: Query<&Map>,
+    mut transforms: Query<&mut Transform, With<Tile>>,
 ) {
-    for map in &mut maps {
+    for map in &maps {
         for pos in map.pos_iter() {
-            let tile_entity = map[pos];
-            let mut tile_data = tiles.get_mut(tile_entity).unwrap();
-
-            match tile_data.tile_type.clone() {
-                TileType::Bomb(Some(bomb)) => {
-                    if Instant::now() > bomb.when_to_explode {
-                        commands.spawn((AudioBundle {
-                            source: audio_assets.explosion_sound.clone(),
-                            settings: PlaybackSettings::DESPAWN,
-                            ..default()
-                        },));
-
-                        // Explode the bomb
-                        tile_data.tile_type = TileType::Explosion(
-                            Some(Instant::now() + EXPLOSION_DURATION),
-                            Box::new(TileType::Empty),
-                        );
-
-                        // increase a bomb counter for the player that placed the bomb
-                        if let Ok(mut player) = query_player.get_mut(bomb.player_entity) {
-                            player.num_bombs += 1;
-                        }
-
-                        for dir in [(0, 1), (0, -1), (1, 0), (-1, 0)] {
-                            for dist in 1..=bomb.firepower {
-                                let pos = pos + IVec2::new(dir.0 * dist, dir.1 * dist);
-                                if !map.contains(pos) {
-                                    continue;
-                                }
-                                let tile_entity = map[pos];
-                                let mut tile_data = tiles.get_mut(tile_entity).unwrap();
-
-                                match &mut tile_data.tile_type {
-                                    TileType::Empty | TileType::PowerUp(_) => {
-                                        tile_data.tile_type = TileType::Explosion(
-                                            Some(Instant::now() + EXPLOSION_DURATION),
-                                            Box::new(TileType::Empty),
-                                        );
-                                    }
-                                    TileType::BreakableWall(contents) => {
-                                        tile_data.tile_type = TileType::Explosion(
-                                            Some(Instant::now() + EXPLOSION_DURATION),
-                                            contents.clone(),
-                                        );
-                                        break; // don't go through walls
-                                    }
-                                    TileType::SolidWall => {
-                                        break; // don't go through walls
-                                    }
-                                    TileType::Bomb(Some(other_bomb)) => {
-                                        other_bomb.when_to_explode = Instant::now();
-                                    }
-                                    _ => {}
-                                }
-                            }
-                        }
-                    }
-                }
-                TileType::Explosion(Some(explosion_time), contents) => {
-                    if Instant::now() > explosion_time {
-                        tile_data.tile_type = *contents.clone();
-                    }
+            let offset = match &sim.tiles()[pos] {
+                TileType::Bomb(Some(bomb)) => bomb_offset(bomb),
+                _ => Vec2::ZERO,
+            };
+            let translation = Vec3::new(
+                (pos.x as f32 + offset.x) * TILE_SIZE,
+                TILE_SIZE / 2.0,
+                (pos.y as f32 + offset.y) * TILE_SIZE,
+            );
+            if let Ok(mut transform) = transforms.get_mut(map[pos]) {
+                if transform.translation != translation {
+                    transform.translation = translation;
                 }
-                _ => {}
             }
         }
     }
 }
 
-fn check_for_death(
+// Keep one model per bomb in the air, arcing over the map
+fn update_thrown_bombs(
     mut commands: Commands,
-    mut players: Query<(Entity, &mut Transform, &Player), (Without<Map>, With<Alive>)>,
-    maps: Query<(&mut Transform, &Map), Without<Player>>,
-    tiles: Query<&Tile>,
+    sim: Res<GameSim>,
+    game_assets: Res<ResourceTileFactory>,
+    map_seed: Res<MapSeed>,
+    mut models: Query<(Entity, &mut Transform), With<ThrownBombModel>>,
 ) {
-    for (player_entity, transform, _player) in &mut players {
-        let pos = transform.translation.xz();
-        for (map_transform, map) in &maps {
-            let pos_in_map = map.get_index_from_position(pos - map_transform.translation.xz());
-            if let Some(pos) = pos_in_map {
-                let tile_entity = map[pos];
-                let tile = tiles.get(tile_entity).unwrap();
-                if let TileType::Explosion(_, _) = tile.tile_type {
-                    // Remove alive component
-                    commands.entity(player_entity).remove::<Alive>();
-                    commands.entity(player_entity).insert(Visibility::Hidden);
-                }
+    let map_size = Vec2::new(sim.tiles().width() as f32, sim.tiles().height() as f32);
+    let mut models = models.iter_mut();
+    for thrown in sim.thrown_bombs() {
+        let position = thrown.position().rem_euclid(map_size);
+        let translation = Vec3::new(
+            position.x * TILE_SIZE,
+            (0.5 + thrown.height()) * TILE_SIZE,
+            position.y * TILE_SIZE,
+        );
+        if let Some((_, mut transform)) = models.next() {
+            transform.translation = translation;
+            continue;
+        }
+
+        let mut entity = commands.spawn(ThrownBombModel);
+        match game_assets.make_tile(&TileType::Bomb(None), &mut map_seed.rng()) {
+            GameAsset::None => {}
+            GameAsset::Scene(scene) => {
+                entity.insert(scene);
+            }
+            GameAsset::AnimatedScene(scene) => {
+                entity.insert(scene);
+            }
+            GameAsset::Mesh(mesh) => {
+                entity.insert(mesh);
             }
+        };
+        entity.insert(Transform::from_translation(translation));
+    }
+
+    // Bombs that have landed are drawn by their tile again
+    for (entity, _) in models {
+        commands.entity(entity).despawn_recursive();
+    }
+}
+
+// A closing wall starts this high above its tile, this long before it lands
+const WALL_FALL_HEIGHT: f32 = 8.0 * TILE_SIZE;
+const WALL_FALL_TICKS: u64 = TICKS_PER_SECOND as u64 / 2;
+
+// Drop a model of the next closing wall onto its tile as it's about to land
+fn update_falling_wall(
+    mut commands: Commands,
+    sim: Res<GameSim>,
+    game_assets: Res<ResourceTileFactory>,
+    map_seed: Res<MapSeed>,
+    mut models: Query<(Entity, &mut Transform), With<FallingWallModel>>,
+) {
+    let falling = sim
+        .next_wall_drop()
+        .filter(|(_, ticks_left)| *ticks_left < WALL_FALL_TICKS);
+    let Some((pos, ticks_left)) = falling else {
+        for (entity, _) in &models {
+            commands.entity(entity).despawn_recursive();
+        }
+        return;
+    };
+
+    // It falls from rest, so it speeds up on the way down
+    let fallen = 1.0 - ticks_left as f32 / WALL_FALL_TICKS as f32;
+    let translation = Vec3::new(
+        pos.x as f32 * TILE_SIZE,
+        TILE_SIZE / 2.0 + WALL_FALL_HEIGHT * (1.0 - fallen * fallen),
+        pos.y as f32 * TILE_SIZE,
+    );
+    if let Some((_, mut transform)) = models.iter_mut().next() {
+        transform.translation = translation;
+        return;
+    }
+
+    let mut entity = commands.spawn(FallingWallModel);
+    match game_assets.make_tile(&TileType::SolidWall, &mut map_seed.tile_rng(pos)) {
+        GameAsset::None => {}
+        GameAsset::Scene(scene) => {
+            entity.insert(scene);
+        }
+        GameAsset::AnimatedScene(scene) => {
+            entity.insert(scene);
+        }
+        GameAsset::Mesh(mesh) => {
+            entity.insert(mesh);
+        }
+    };
+    entity.insert(Transform::from_translation(translation));
+}
+
+// Counts down to sudden death, or up through rounds that have none
+fn update_round_clock(sim: Res<GameSim>, mut clocks: Query<&mut Text, With<RoundClockText>>) {
+    let ticks_per_second = TICKS_PER_SECOND as u64;
+    let clock = |seconds: u64| format!("{}:{:02}", seconds / 60, seconds % 60);
+    let text = match sim.rules().sudden_death.map(u64::from) {
+        Some(start) if sim.tick() >= start => "Sudden death!".to_string(),
+        Some(start) => clock((start - sim.tick()).div_ceil(ticks_per_second)),
+        None => clock(sim.tick() / ticks_per_second),
+    };
+    for mut clock in &mut clocks {
+        if clock.sections[0].value != text {
+            clock.sections[0].value = text.clone();
         }
     }
 }
 
 fn check_for_win(
     mut next_state: ResMut<NextState<GameState>>,
-    query_alive: Query<(Entity, &Transform, &Player), With<Alive>>,
-    query_dead: Query<(Entity, &Transform, &Player), Without<Alive>>,
+    sim: Res<GameSim>,
+    models: Query<(Entity, &PlayerModel)>,
     query_children: Query<&Children>,
     mut query_transitions: Query<(&mut AnimationTransitions, &mut AnimationPlayer)>,
 ) {
-    let num_alive = query_alive.iter().count();
-    let num_dead = query_dead.iter().count();
-    let num_players = num_alive + num_dead;
-
-    if num_players > 1 {
-        if num_alive == 1 {
-            if let Ok((player_entity, _, _)) = query_alive.get_single() {
+    match sim.outcome() {
+        Some(Outcome::Win(winner)) => {
+            for (player_entity, model) in &models {
+                if model.player_index != winner {
+                    continue;
+                }
                 let target_animation = AnimalAnimation::Jump.to_node_index();
 
                 play_animation(
@@ -715,21 +1446,55 @@ fn check_for_win(
                     &query_children,
                     &mut query_transitions,
                 );
-
-                // Transition to GameOver state
-                next_state.set(GameState::GameOver);
             }
-        } else if num_alive == 0 {
-            // Draw
+
+            // Transition to GameOver state
             next_state.set(GameState::GameOver);
         }
+        Some(Outcome::Draw) => {
+            next_state.set(GameState::GameOver);
+        }
+        None => {}
     }
 }
 
-fn game_over(mut commands: Commands, asset_server: Res<AssetServer>) {
+fn game_over(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    map_seed: Res<MapSeed>,
+    recorder: Option<Res<ReplayRecorder>>,
+    session: Option<Res<OnlineSession>>,
+    connection: Option<Res<ServerConnection>>,
+) {
+    #[cfg(not(target_arch = "wasm32"))]
+    if let Some(recorder) = recorder {
+        let timestamp = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_secs());
+        let path = std::path::PathBuf::from(format!("replays/{}.kcreplay", timestamp));
+        match recorder.0.save(&path) {
+            Ok(()) => println!("Replay saved to {}", path.display()),
+            Err(err) => eprintln!("Unable to save replay to {}: {}", path.display(), err),
+        }
+    }
+    #[cfg(target_arch = "wasm32")]
+    let _ = recorder;
+
     commands.spawn(
         TextBundle::from_section(
-            "Game Over! Press SPACE to restart",
+            if session.is_some() {
+                format!("Game Over!\nMap seed: {}", map_seed.0)
+            } else if connection.is_some() {
+                format!(
+                    "Game Over! Next round starting...\nMap seed: {}",
+                    map_seed.0
+                )
+            } else {
+                format!(
+                    "Game Over! Press SPACE to restart\nMap seed: {}",
+                    map_seed.0
+                )
+            },
             TextStyle {
                 font: asset_server.load("fonts/Handjet/Handjet-Medium.ttf"),
                 font_size: 40.0,
@@ -745,6 +1510,7 @@ fn game_over(mut commands: Commands, asset_server: Res<AssetServer>) {
     );
 }
 
+#[allow(clippy::too_many_arguments)]
 fn restart_game(
     mut next_state: ResMut<NextState<GameState>>,
     keyboard_input: Res<ButtonInput<KeyCode>>,
@@ -752,8 +1518,16 @@ fn restart_game(
     tiles: Query<Entity, With<Tile>>,
     maps: Query<Entity, With<Map>>,
     text: Query<Entity, With<Text>>,
+    sim: Res<GameSim>,
+    connection: Option<Res<ServerConnection>>,
 ) {
-    if keyboard_input.just_pressed(KeyCode::Space) {
+    // A server starts the next round by itself
+    let restart = match connection {
+        Some(_) => sim.outcome().is_none(),
+        None => keyboard_input.just_pressed(KeyCode::Space),
+    };
+
+    if restart {
         // Despawn maps and tiles
         for entity in tiles.iter().chain(maps.iter()) {
             commands.entity(entity).despawn();
@@ -768,31 +1542,3 @@ fn restart_game(
         next_state.set(GameState::Playing);
     }
 }
-
-fn check_pickup(
-    mut query_players: Query<(&Transform, &mut Player), (Without<Map>, With<Alive>)>,
-    mut query_tiles: Query<&mut Tile>,
-    query_maps: Query<(&mut Transform, &Map), Without<Player>>,
-) {
-    for (transform, mut player) in &mut query_players {
-        let pos = transform.translation.xz();
-        for (map_transform, map) in &query_maps {
-            let pos_in_map = map.get_index_from_position(pos - map_transform.translation.xz());
-            if let Some(pos) = pos_in_map {
-                let tile_entity = map[pos];
-                let mut tile = query_tiles.get_mut(tile_entity).unwrap();
-                match tile.tile_type {
-                    TileType::PowerUp(PowerUpType::Firepower) => {
-                        player.firepower += 1;
-                        tile.tile_type = TileType::Empty;
-                    }
-                    TileType::PowerUp(PowerUpType::ExtraBomb) => {
-                        player.num_bombs += 1;
-                        tile.tile_type = TileType::Empty;
-                    }
-                    _ => {}
-                }
-            }
-        }
-    }
-}
@@ -1,14 +1,45 @@
 use crate::tile::*;
 use bevy::prelude::*;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
 
-#[derive(Component)]
-pub struct Map {
+// Drives every random choice made while creating a map, so that the same seed
+// always reproduces the same map
+#[derive(Resource, Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+pub struct MapSeed(pub u64);
+
+impl MapSeed {
+    pub fn random() -> Self {
+        Self(rand::random())
+    }
+
+    pub fn rng(&self) -> ChaCha8Rng {
+        ChaCha8Rng::seed_from_u64(self.0)
+    }
+
+    // An independent random stream for a single tile, so that choices such as
+    // a wall's look don't depend on the order tiles are visited in
+    pub fn tile_rng(&self, pos: IVec2) -> ChaCha8Rng {
+        let mut rng = self.rng();
+        rng.set_stream(1 + ((pos.x as u32 as u64) << 32 | pos.y as u32 as u64));
+        rng
+    }
+}
+
+#[derive(Component, Clone, Debug, Default)]
+pub struct Grid<T: Send + Sync + 'static> {
     width: usize,
     height: usize,
-    grid_entities: Vec<Entity>,
+    cells: Vec<T>,
     player_spawn_points: Vec<IVec2>,
 }
 
+// The grid of tile entities rendered by the game
+pub type Map = Grid<Entity>;
+
+// The grid of tile states owned by the simulation
+pub type TileGrid = Grid<TileType>;
+
 impl Map {
     pub fn new_empty(commands: &mut Commands, width: usize, height: usize) -> Self {
         let mut entities = Vec::new();
@@ -33,11 +64,37 @@ impl Map {
         Self {
             width,
             height,
-            grid_entities: entities,
+            cells: entities,
+            player_spawn_points: vec![],
+        }
+    }
+
+    pub fn from_tile_grid(commands: &mut Commands, tiles: &TileGrid) -> Self {
+        let mut map = Self::new_empty(commands, tiles.width(), tiles.height());
+        map.set_spawn_points(tiles.spawn_points().to_vec());
+
+        for pos in map.pos_iter() {
+            commands.entity(map[pos]).insert(Tile {
+                tile_type: tiles[pos].clone(),
+            });
+        }
+
+        map
+    }
+}
+
+impl<T: Clone + Send + Sync + 'static> Grid<T> {
+    pub fn filled(width: usize, height: usize, value: T) -> Self {
+        Self {
+            width,
+            height,
+            cells: vec![value; width * height],
             player_spawn_points: vec![],
         }
     }
+}
 
+impl<T: Send + Sync + 'static> Grid<T> {
     pub fn height(&self) -> usize {
         self.height
     }
@@ -90,17 +147,17 @@ impl Map {
     }
 }
 
-impl std::ops::Index<IVec2> for Map {
-    type Output = Entity;
+impl<T: Send + Sync + 'static> std::ops::Index<IVec2> for Grid<T> {
+    type Output = T;
 
     fn index(&self, pos: IVec2) -> &Self::Output {
-        &self.grid_entities[self.index_from_position(pos)]
+        &self.cells[self.index_from_position(pos)]
     }
 }
 
-impl std::ops::IndexMut<IVec2> for Map {
+impl<T: Send + Sync + 'static> std::ops::IndexMut<IVec2> for Grid<T> {
     fn index_mut(&mut self, pos: IVec2) -> &mut Self::Output {
         let idx = self.index_from_position(pos);
-        &mut self.grid_entities[idx]
+        &mut self.cells[idx]
     }
 }
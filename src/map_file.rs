@@ -0,0 +1,167 @@
+use crate::errors::invalid_data;
+use crate::map::TileGrid;
+use crate::tile::*;
+use bevy::prelude::*;
+use std::path::Path;
+
+// Maps are stored as text with one character per tile and one line per row:
+//
+//   '#'  solid wall
+//   '+'  breakable wall
+//   'f'  breakable wall hiding a firepower power-up
+//   'b'  breakable wall hiding an extra bomb power-up
+//   'k'  breakable wall hiding a kick power-up
+//   'g'  breakable wall hiding a glove power-up
+//   'r'  breakable wall hiding a remote power-up
+//   's'  breakable wall hiding a speed power-up
+//   'p'  breakable wall hiding a pierce power-up
+//   'l'  breakable wall hiding a line bomb power-up
+//   'c'  breakable wall hiding a skull (curse)
+//   'h'  breakable wall hiding a shield
+//   'F'  firepower power-up
+//   'B'  extra bomb power-up
+//   'K'  kick power-up
+//   'G'  glove power-up
+//   'R'  remote power-up
+//   'S'  speed power-up
+//   'P'  pierce power-up
+//   'L'  line bomb power-up
+//   'C'  skull
+//   'H'  shield
+//   '.'  empty, as is ' '
+//   '0'..'9'  spawn point of that player, on an empty tile
+pub fn parse_map(text: &str) -> std::io::Result<TileGrid> {
+    let rows: Vec<&str> = text.lines().filter(|line| !line.is_empty()).collect();
+    let height = rows.len();
+    let width = rows.first().map_or(0, |row| row.chars().count());
+    if width == 0 {
+        return Err(invalid_data("map is empty"));
+    }
+
+    let hidden = |power_up| TileType::BreakableWall(Box::new(TileType::PowerUp(power_up)));
+    let mut map = TileGrid::filled(width, height, TileType::Empty);
+    let mut spawn_points = Vec::new();
+    for (y, row) in rows.iter().enumerate() {
+        if row.chars().count() != width {
+            return Err(invalid_data(&format!(
+                "row {} is not {} tiles wide",
+                y + 1,
+                width
+            )));
+        }
+        for (x, c) in row.chars().enumerate() {
+            let pos = IVec2::new(x as i32, y as i32);
+            map[pos] = match c {
+                '#' => TileType::SolidWall,
+                '+' => TileType::BreakableWall(Box::new(TileType::Empty)),
+                'f' => hidden(PowerUpType::Firepower),
+                'b' => hidden(PowerUpType::ExtraBomb),
+                'k' => hidden(PowerUpType::Kick),
+                'g' => hidden(PowerUpType::Glove),
+                'r' => hidden(PowerUpType::Remote),
+                's' => hidden(PowerUpType::Speed),
+                'p' => hidden(PowerUpType::Pierce),
+                'l' => hidden(PowerUpType::LineBomb),
+                'c' => hidden(PowerUpType::Skull),
+                'h' => hidden(PowerUpType::Shield),
+                'F' => TileType::PowerUp(PowerUpType::Firepower),
+                'B' => TileType::PowerUp(PowerUpType::ExtraBomb),
+                'K' => TileType::PowerUp(PowerUpType::Kick),
+                'G' => TileType::PowerUp(PowerUpType::Glove),
+                'R' => TileType::PowerUp(PowerUpType::Remote),
+                'S' => TileType::PowerUp(PowerUpType::Speed),
+                'P' => TileType::PowerUp(PowerUpType::Pierce),
+                'L' => TileType::PowerUp(PowerUpType::LineBomb),
+                'C' => TileType::PowerUp(PowerUpType::Skull),
+                'H' => TileType::PowerUp(PowerUpType::Shield),
+                '.' | ' ' => TileType::Empty,
+                '0'..='9' => {
+                    spawn_points.push((c, pos));
+                    TileType::Empty
+                }
+                _ => {
+                    return Err(invalid_data(&format!(
+                        "unknown tile `{}` at ({}, {})",
+                        c, x, y
+                    )))
+                }
+            };
+        }
+    }
+
+    spawn_points.sort_by_key(|(c, _)| *c);
+    map.set_spawn_points(spawn_points.into_iter().map(|(_, pos)| pos).collect());
+    Ok(map)
+}
+
+// The text of a map as read by `parse_map`. Tiles such as bombs and
+// explosions, which a map doesn't start with, are written as empty.
+pub fn map_to_text(map: &TileGrid) -> String {
+    let mut text = String::new();
+    for y in 0..map.height() as i32 {
+        for x in 0..map.width() as i32 {
+            let pos = IVec2::new(x, y);
+            let spawn = map.spawn_points().iter().position(|spawn| *spawn == pos);
+            text.push(match (&map[pos], spawn) {
+                (TileType::SolidWall, _) => '#',
+                (TileType::BreakableWall(contents), _) => match **contents {
+                    TileType::PowerUp(PowerUpType::Firepower) => 'f',
+                    TileType::PowerUp(PowerUpType::ExtraBomb) => 'b',
+                    TileType::PowerUp(PowerUpType::Kick) => 'k',
+                    TileType::PowerUp(PowerUpType::Glove) => 'g',
+                    TileType::PowerUp(PowerUpType::Remote) => 'r',
+                    TileType::PowerUp(PowerUpType::Speed) => 's',
+                    TileType::PowerUp(PowerUpType::Pierce) => 'p',
+                    TileType::PowerUp(PowerUpType::LineBomb) => 'l',
+                    TileType::PowerUp(PowerUpType::Skull) => 'c',
+                    TileType::PowerUp(PowerUpType::Shield) => 'h',
+                    _ => '+',
+                },
+                (TileType::PowerUp(PowerUpType::Firepower), _) => 'F',
+                (TileType::PowerUp(PowerUpType::ExtraBomb), _) => 'B',
+                (TileType::PowerUp(PowerUpType::Kick), _) => 'K',
+                (TileType::PowerUp(PowerUpType::Glove), _) => 'G',
+                (TileType::PowerUp(PowerUpType::Remote), _) => 'R',
+                (TileType::PowerUp(PowerUpType::Speed), _) => 'S',
+                (TileType::PowerUp(PowerUpType::Pierce), _) => 'P',
+                (TileType::PowerUp(PowerUpType::LineBomb), _) => 'L',
+                (TileType::PowerUp(PowerUpType::Skull), _) => 'C',
+                (TileType::PowerUp(PowerUpType::Shield), _) => 'H',
+                (_, Some(index)) if index < 10 => char::from(b'0' + index as u8),
+                _ => '.',
+            });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+pub fn load_map(path: &Path) -> std::io::Result<TileGrid> {
+    parse_map(&std::fs::read_to_string(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic::*;
+    use crate::map::MapSeed;
+
+    #[test]
+    fn test_map_text_round_trip() {
+        let map = make_basic_map(MAP_DIMENSIONS.0, MAP_DIMENSIONS.1, &mut MapSeed(3).rng());
+        let text = map_to_text(&map);
+        let loaded = parse_map(&text).unwrap();
+
+        assert_eq!(
+            (loaded.width(), loaded.height()),
+            (map.width(), map.height())
+        );
+        assert_eq!(loaded.spawn_points(), map.spawn_points());
+        for pos in map.pos_iter() {
+            assert_eq!(loaded[pos], map[pos]);
+        }
+
+        assert!(parse_map("###\n#0\n###\n").is_err());
+        assert!(parse_map("#x#\n").is_err());
+    }
+}
@@ -0,0 +1,247 @@
+use crate::map::{Grid, TileGrid};
+use crate::sdf::is_walkable;
+use crate::tile::*;
+use bevy::prelude::*;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, VecDeque};
+
+pub const DIRECTIONS: [IVec2; 4] = [IVec2::Y, IVec2::NEG_Y, IVec2::X, IVec2::NEG_X];
+
+// What it costs to enter each kind of tile when searching for the cheapest
+// path. Tiles that can't be walked through are avoided unless given a cost.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NavCosts {
+    pub open: u32,
+    // E.g. less than `open` to take detours past power-ups
+    pub power_up: u32,
+    // Going through a breakable wall by bombing it, or `None` to go around
+    pub breakable_wall: Option<u32>,
+    // Added for a tile that a blast will reach, or `None` to avoid them
+    pub threatened: Option<u32>,
+}
+
+impl Default for NavCosts {
+    fn default() -> Self {
+        Self {
+            open: 1,
+            power_up: 1,
+            breakable_wall: None,
+            threatened: None,
+        }
+    }
+}
+
+impl NavCosts {
+    // The cost of entering a tile, given when a blast reaches it as returned
+    // by `danger::blast_times`
+    pub fn cost(&self, tile: &TileType, blast_time: Option<u32>) -> Option<u32> {
+        let cost = match tile {
+            TileType::PowerUp(_) => self.power_up,
+            TileType::BreakableWall(_) => self.breakable_wall?,
+            tile if is_walkable(tile) => self.open,
+            _ => return None,
+        };
+        match blast_time {
+            Some(_) => Some(cost + self.threatened?),
+            None => Some(cost),
+        }
+    }
+
+    fn cheapest(&self) -> u32 {
+        self.open
+            .min(self.power_up)
+            .min(self.breakable_wall.unwrap_or(u32::MAX))
+    }
+}
+
+// Follows `came_from` back from `end`, returning the tiles after `start`
+fn trace_path(came_from: &Grid<Option<IVec2>>, start: IVec2, end: IVec2) -> Vec<IVec2> {
+    let mut path = vec![end];
+    while let Some(prev) = came_from[*path.last().unwrap()].filter(|prev| *prev != start) {
+        path.push(prev);
+    }
+    path.reverse();
+    path
+}
+
+// Breadth-first search from `start` over walkable tiles, returning the path to
+// the nearest tile accepted by `is_goal`. `can_enter` is given each tile with
+// how many steps from `start` it is. The path doesn't include `start`, unless
+// `start` is the goal.
+pub fn bfs(
+    tiles: &TileGrid,
+    start: IVec2,
+    can_enter: impl Fn(IVec2, u32) -> bool,
+    is_goal: impl Fn(IVec2) -> bool,
+) -> Option<Vec<IVec2>> {
+    bfs_in_order(tiles, start, &DIRECTIONS, can_enter, is_goal)
+}
+
+// As `bfs`, trying neighbours in the given order, which decides between
+// paths of the same length
+pub fn bfs_in_order(
+    tiles: &TileGrid,
+    start: IVec2,
+    directions: &[IVec2],
+    can_enter: impl Fn(IVec2, u32) -> bool,
+    is_goal: impl Fn(IVec2) -> bool,
+) -> Option<Vec<IVec2>> {
+    let mut came_from: Grid<Option<IVec2>> = Grid::filled(tiles.width(), tiles.height(), None);
+    let mut queue = VecDeque::from([(start, 0)]);
+    came_from[start] = Some(start);
+
+    while let Some((pos, dist)) = queue.pop_front() {
+        if is_goal(pos) {
+            return Some(trace_path(&came_from, start, pos));
+        }
+
+        for dir in directions {
+            let next = pos + *dir;
+            if !tiles.contains(next)
+                || came_from[next].is_some()
+                || !is_walkable(&tiles[next])
+                || !can_enter(next, dist + 1)
+            {
+                continue;
+            }
+            came_from[next] = Some(pos);
+            queue.push_back((next, dist + 1));
+        }
+    }
+
+    None
+}
+
+// Every tile that can be reached from `start` through tiles accepted by
+// `can_enter`, in breadth-first order, starting with `start`
+pub fn flood_fill(tiles: &TileGrid, start: IVec2, can_enter: impl Fn(IVec2) -> bool) -> Vec<IVec2> {
+    let mut seen: Grid<bool> = Grid::filled(tiles.width(), tiles.height(), false);
+    let mut reached = vec![start];
+    seen[start] = true;
+    let mut index = 0;
+    while let Some(pos) = reached.get(index).copied() {
+        index += 1;
+        for dir in DIRECTIONS {
+            let next = pos + dir;
+            if tiles.contains(next) && !seen[next] && can_enter(next) {
+                seen[next] = true;
+                reached.push(next);
+            }
+        }
+    }
+    reached
+}
+
+// The cheapest path from `start` to `goal`, with its cost. `blast_times` is
+// used for the cost of threatened tiles, see `danger::blast_times`.
+pub fn a_star(
+    tiles: &TileGrid,
+    blast_times: &Grid<Option<u32>>,
+    costs: &NavCosts,
+    start: IVec2,
+    goal: IVec2,
+) -> Option<(Vec<IVec2>, u32)> {
+    let heuristic = |pos: IVec2| {
+        let dist = (goal - pos).abs();
+        (dist.x + dist.y) as u32 * costs.cheapest()
+    };
+
+    let mut came_from: Grid<Option<IVec2>> = Grid::filled(tiles.width(), tiles.height(), None);
+    let mut best_cost: Grid<u32> = Grid::filled(tiles.width(), tiles.height(), u32::MAX);
+    // Ties are broken by tile so that the same map always gives the same path
+    let mut open = BinaryHeap::from([Reverse((heuristic(start), 0, start.x, start.y))]);
+    came_from[start] = Some(start);
+    best_cost[start] = 0;
+
+    while let Some(Reverse((_, cost, x, y))) = open.pop() {
+        let pos = IVec2::new(x, y);
+        if pos == goal {
+            return Some((trace_path(&came_from, start, goal), cost));
+        }
+        if cost > best_cost[pos] {
+            continue;
+        }
+
+        for dir in DIRECTIONS {
+            let next = pos + dir;
+            if !tiles.contains(next) {
+                continue;
+            }
+            let Some(step_cost) = costs.cost(&tiles[next], blast_times[next]) else {
+                continue;
+            };
+            let next_cost = cost + step_cost;
+            if next_cost < best_cost[next] {
+                best_cost[next] = next_cost;
+                came_from[next] = Some(pos);
+                open.push(Reverse((
+                    next_cost + heuristic(next),
+                    next_cost,
+                    next.x,
+                    next.y,
+                )));
+            }
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::danger::blast_times;
+
+    #[test]
+    fn test_paths_follow_costs() {
+        // . . . . .
+        // . + # . .
+        // . . . . .
+        let mut tiles = TileGrid::filled(5, 3, TileType::Empty);
+        tiles[IVec2::new(1, 1)] = TileType::BreakableWall(Box::new(TileType::Empty));
+        tiles[IVec2::new(2, 1)] = TileType::SolidWall;
+        let start = IVec2::new(0, 1);
+        let goal = IVec2::new(4, 1);
+        let no_danger = Grid::filled(5, 3, None);
+
+        let (path, cost) = a_star(&tiles, &no_danger, &NavCosts::default(), start, goal).unwrap();
+        assert_eq!(cost, 6);
+        assert_eq!(path.len(), 6);
+        assert_eq!(path.last(), Some(&goal));
+
+        // Bombing through the wall doesn't help with the solid wall behind it
+        let bomb_through = NavCosts {
+            breakable_wall: Some(1),
+            ..default()
+        };
+        assert_eq!(
+            a_star(&tiles, &no_danger, &bomb_through, start, goal)
+                .unwrap()
+                .1,
+            6
+        );
+
+        // A bomb threatens the top row, so the path goes along the bottom
+        tiles[IVec2::new(2, 0)] = TileType::Bomb(Some(Bomb::new(60, 1, 0)));
+        let danger = blast_times(&tiles, 0);
+        let (path, _) = a_star(&tiles, &danger, &NavCosts::default(), start, goal).unwrap();
+        assert!(path.contains(&IVec2::new(2, 2)));
+        let through_danger = NavCosts {
+            threatened: Some(0),
+            ..default()
+        };
+        assert!(a_star(&tiles, &danger, &through_danger, start, IVec2::new(1, 0)).is_some());
+        assert!(a_star(
+            &tiles,
+            &danger,
+            &NavCosts::default(),
+            start,
+            IVec2::new(1, 0)
+        )
+        .is_none());
+
+        // Unweighted search, only through walkable tiles
+        let path = bfs(&tiles, start, |_, _| true, |pos| pos == IVec2::new(3, 1)).unwrap();
+        assert_eq!(path.len(), 5);
+    }
+}
@@ -0,0 +1,413 @@
+use crate::checksum::StateDigest;
+use crate::errors::invalid_data;
+use crate::player::*;
+use crate::replay::{action_from_byte, action_to_byte, ByteReader};
+use crate::sim::*;
+use bevy::prelude::*;
+use std::collections::VecDeque;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+
+const PACKET_MAGIC: &[u8; 4] = b"KCNT";
+
+const INPUTS: u8 = 0;
+const DIGEST: u8 = 1;
+
+// How many ticks the local simulation may run ahead of the inputs that every
+// peer has confirmed. Beyond this the session waits for the slowest peer.
+pub const MAX_PREDICTION_TICKS: u64 = 8;
+
+// Unacknowledged inputs are sent again in every packet, up to this many
+const MAX_INPUTS_PER_PACKET: usize = 64;
+
+// How many confirmed states are kept to compare with the peers' checksums
+const MAX_DIGESTS: usize = 2 * TICKS_PER_SECOND as usize;
+
+// An online match using rollback. Each peer runs the whole simulation and
+// sends only its own inputs. Inputs that haven't arrived yet are predicted,
+// and when a prediction turns out wrong the simulation is rewound to that
+// tick and stepped again with the real inputs.
+pub struct RollbackSession {
+    socket: UdpSocket,
+    local_player: usize,
+    // Address of every player, including the local one
+    peers: Vec<SocketAddr>,
+    // Every known input of each player, indexed by tick
+    inputs: Vec<Vec<PlayerControl>>,
+    // How many of our inputs each peer has received
+    acks: Vec<usize>,
+    sim: GameSim,
+    // The first tick for which some player's input is still missing
+    confirmed_tick: u64,
+    // For each tick from `confirmed_tick`, the state before the tick and the
+    // controls it was stepped with
+    history: VecDeque<(GameSim, Vec<PlayerControl>)>,
+    // Digests of the latest confirmed states, which every peer must agree on
+    digests: VecDeque<StateDigest>,
+    desynced: bool,
+}
+
+impl RollbackSession {
+    // Starts a match with one player per address. `sim` must be the same on
+    // every peer, so it should be made from a shared seed.
+    pub fn new(
+        socket: UdpSocket,
+        local_player: usize,
+        peers: Vec<SocketAddr>,
+        mut sim: GameSim,
+    ) -> std::io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        for player_index in 0..peers.len() {
+            sim.add_player(PlayerController::Online(player_index));
+        }
+
+        Ok(Self {
+            socket,
+            local_player,
+            inputs: vec![Vec::new(); peers.len()],
+            acks: vec![0; peers.len()],
+            peers,
+            sim,
+            confirmed_tick: 0,
+            history: VecDeque::new(),
+            digests: VecDeque::new(),
+            desynced: false,
+        })
+    }
+
+    pub fn local_player(&self) -> usize {
+        self.local_player
+    }
+
+    // The latest state, which may include predicted inputs
+    pub fn sim(&self) -> &GameSim {
+        &self.sim
+    }
+
+    pub fn confirmed_tick(&self) -> u64 {
+        self.confirmed_tick
+    }
+
+    // Whether a peer's game has been found to differ from ours
+    pub fn is_desynced(&self) -> bool {
+        self.desynced
+    }
+
+    // Exchange inputs with the peers and step the simulation with the local
+    // player's control. Returns `None` without stepping while waiting for a
+    // peer that has fallen too far behind.
+    pub fn advance(&mut self, local_control: PlayerControl) -> Option<Vec<SimEvent>> {
+        self.receive();
+
+        let mut events = None;
+        if self.sim.tick() < self.confirmed_tick + MAX_PREDICTION_TICKS {
+            self.inputs[self.local_player].push(local_control);
+            events = Some(self.step());
+        }
+
+        self.confirm();
+        self.send();
+        events
+    }
+
+    fn step(&mut self) -> Vec<SimEvent> {
+        let controls = self.controls_for(self.sim.tick());
+        self.history.push_back((self.sim.clone(), controls.clone()));
+        self.sim.step(&controls)
+    }
+
+    // The control of every player at `tick`. A missing input is predicted to
+    // keep the motion of the player's last known one.
+    fn controls_for(&self, tick: u64) -> Vec<PlayerControl> {
+        self.inputs
+            .iter()
+            .map(|inputs| match inputs.get(tick as usize) {
+                Some(control) => *control,
+                None => inputs
+                    .last()
+                    .map_or_else(PlayerControl::default, |last| PlayerControl {
+                        motion: last.motion,
+                        action: PlayerAction::None,
+                    }),
+            })
+            .collect()
+    }
+
+    fn receive(&mut self) {
+        let mut buf = [0u8; 2048];
+        let mut rollback_tick: Option<u64> = None;
+
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    eprintln!("Unable to receive from peers: {}", err);
+                    break;
+                }
+            };
+
+            match self.read_packet(&buf[..len], from) {
+                Ok(Some(tick)) => {
+                    rollback_tick = Some(rollback_tick.map_or(tick, |earliest| earliest.min(tick)))
+                }
+                Ok(None) => {}
+                Err(err) => eprintln!("Ignoring packet from {}: {}", from, err),
+            }
+        }
+
+        if let Some(tick) = rollback_tick {
+            self.rollback(tick);
+        }
+    }
+
+    // Returns the first tick that was stepped with a wrong prediction
+    fn read_packet(&mut self, bytes: &[u8], from: SocketAddr) -> std::io::Result<Option<u64>> {
+        let mut reader = ByteReader::new(bytes);
+        if reader.take(4)? != PACKET_MAGIC {
+            return Err(invalid_data("not a game packet"));
+        }
+        let kind = reader.u8()?;
+
+        let sender = reader.u8()? as usize;
+        if sender == self.local_player || self.peers.get(sender) != Some(&from) {
+            return Err(invalid_data("unknown sender"));
+        }
+
+        match kind {
+            INPUTS => self.read_inputs(&mut reader, sender),
+            DIGEST => {
+                self.read_digest(&mut reader, sender)?;
+                Ok(None)
+            }
+            _ => Err(invalid_data(&format!("unknown packet kind {}", kind))),
+        }
+    }
+
+    fn read_inputs(
+        &mut self,
+        reader: &mut ByteReader,
+        sender: usize,
+    ) -> std::io::Result<Option<u64>> {
+        let ack = reader.u32()? as usize;
+        self.acks[sender] = self.acks[sender].max(ack);
+
+        let start = reader.u32()? as usize;
+        let mut mispredicted = None;
+        for i in 0..reader.u8()? as usize {
+            let motion = Vec2::new(reader.f32()?, reader.f32()?);
+            let action = action_from_byte(reader.u8()?)?;
+            let control = PlayerControl { motion, action };
+
+            // Skip inputs we already have, or that arrived ahead of a lost one
+            let tick = start + i;
+            let inputs = &mut self.inputs[sender];
+            if tick != inputs.len() {
+                continue;
+            }
+            inputs.push(control);
+
+            let stepped_with = (tick as u64)
+                .checked_sub(self.confirmed_tick)
+                .and_then(|index| self.history.get(index as usize));
+            if let Some((_, controls)) = stepped_with {
+                if controls[sender] != control && mispredicted.is_none() {
+                    mispredicted = Some(tick as u64);
+                }
+            }
+        }
+
+        // The checksum of the sender's latest confirmed state
+        let checksum_tick = reader.u32()?;
+        let checksum = reader.u64()?;
+        let own = self
+            .digests
+            .iter()
+            .find(|digest| digest.tick == checksum_tick as u64);
+        if let Some(own) = own {
+            if own.checksum() != checksum && !self.desynced {
+                eprintln!("Desync with player {} at tick {}", sender, checksum_tick);
+                self.desynced = true;
+                // Both peers send their digest, so each can find the difference
+                self.send_digest(sender, own);
+            }
+        }
+
+        Ok(mispredicted)
+    }
+
+    fn read_digest(&mut self, reader: &mut ByteReader, sender: usize) -> std::io::Result<()> {
+        let mut digest = StateDigest {
+            tick: reader.u64()?,
+            map_width: reader.u16()? as usize,
+            map_height: reader.u16()? as usize,
+            elements: Vec::new(),
+        };
+        for _ in 0..reader.u16()? {
+            digest.elements.push(reader.u32()?);
+        }
+
+        match self.digests.iter().find(|own| own.tick == digest.tick) {
+            Some(own) => match own.first_difference(&digest) {
+                Some(difference) => eprintln!(
+                    "Desync with player {} at tick {}: first difference is {}",
+                    sender, digest.tick, difference
+                ),
+                None => eprintln!(
+                    "Player {} reported a desync at tick {}, but its state matches ours",
+                    sender, digest.tick
+                ),
+            },
+            None => eprintln!(
+                "Desync with player {} at tick {}, which is too long ago to compare",
+                sender, digest.tick
+            ),
+        }
+        Ok(())
+    }
+
+    fn send_digest(&self, peer: usize, digest: &StateDigest) {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(PACKET_MAGIC);
+        bytes.push(DIGEST);
+        bytes.push(self.local_player as u8);
+        bytes.extend_from_slice(&digest.tick.to_le_bytes());
+        bytes.extend_from_slice(&(digest.map_width as u16).to_le_bytes());
+        bytes.extend_from_slice(&(digest.map_height as u16).to_le_bytes());
+        bytes.extend_from_slice(&(digest.elements.len() as u16).to_le_bytes());
+        for element in &digest.elements {
+            bytes.extend_from_slice(&element.to_le_bytes());
+        }
+
+        let addr = self.peers[peer];
+        if let Err(err) = self.socket.send_to(&bytes, addr) {
+            eprintln!("Unable to send to {}: {}", addr, err);
+        }
+    }
+
+    // Rewind to the state before `tick` and step forward again to the current
+    // tick. Events from the repeated ticks are dropped, since the view has
+    // already reacted to their first run.
+    fn rollback(&mut self, tick: u64) {
+        let index = (tick - self.confirmed_tick) as usize;
+        let current_tick = self.sim.tick();
+
+        self.sim = self.history[index].0.clone();
+        self.history.truncate(index);
+        while self.sim.tick() < current_tick {
+            self.step();
+        }
+    }
+
+    // Forget the history of ticks that every player's input has arrived for,
+    // keeping only a digest of their states
+    fn confirm(&mut self) {
+        let complete = self.inputs.iter().map(Vec::len).min().unwrap_or(0) as u64;
+        while self.confirmed_tick < complete.min(self.sim.tick()) {
+            if let Some((state, _)) = self.history.pop_front() {
+                if self.digests.len() == MAX_DIGESTS {
+                    self.digests.pop_front();
+                }
+                self.digests.push_back(StateDigest::of(&state));
+            }
+            self.confirmed_tick += 1;
+        }
+    }
+
+    fn send(&self) {
+        let local_inputs = &self.inputs[self.local_player];
+
+        for (peer, addr) in self.peers.iter().enumerate() {
+            if peer == self.local_player {
+                continue;
+            }
+
+            let start = self.acks[peer].min(local_inputs.len());
+            let end = local_inputs.len().min(start + MAX_INPUTS_PER_PACKET);
+
+            let mut bytes = Vec::new();
+            bytes.extend_from_slice(PACKET_MAGIC);
+            bytes.push(INPUTS);
+            bytes.push(self.local_player as u8);
+            bytes.extend_from_slice(&(self.inputs[peer].len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&(start as u32).to_le_bytes());
+            bytes.push((end - start) as u8);
+            for control in &local_inputs[start..end] {
+                bytes.extend_from_slice(&control.motion.x.to_le_bytes());
+                bytes.extend_from_slice(&control.motion.y.to_le_bytes());
+                bytes.push(action_to_byte(control.action));
+            }
+
+            match self.digests.back() {
+                Some(digest) => {
+                    bytes.extend_from_slice(&(digest.tick as u32).to_le_bytes());
+                    bytes.extend_from_slice(&digest.checksum().to_le_bytes());
+                }
+                None => {
+                    bytes.extend_from_slice(&u32::MAX.to_le_bytes());
+                    bytes.extend_from_slice(&0u64.to_le_bytes());
+                }
+            }
+
+            if let Err(err) = self.socket.send_to(&bytes, addr) {
+                eprintln!("Unable to send to {}: {}", addr, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::map::TileGrid;
+    use crate::tile::TileType;
+
+    #[test]
+    fn test_sessions_agree_after_rollback() {
+        let mut tiles = TileGrid::filled(9, 9, TileType::Empty);
+        tiles.set_spawn_points(vec![IVec2::new(1, 1), IVec2::new(7, 7)]);
+        let sim = GameSim::new(tiles);
+
+        let sockets = [
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+            UdpSocket::bind("127.0.0.1:0").unwrap(),
+        ];
+        let peers: Vec<_> = sockets.iter().map(|s| s.local_addr().unwrap()).collect();
+        let mut sessions: Vec<_> = sockets
+            .into_iter()
+            .enumerate()
+            .map(|(i, socket)| RollbackSession::new(socket, i, peers.clone(), sim.clone()).unwrap())
+            .collect();
+
+        let left = PlayerControl {
+            motion: Vec2::new(-1.0, 0.0),
+            action: PlayerAction::None,
+        };
+
+        // Player 0 runs ahead, predicting that player 1 stands still
+        for _ in 0..5 {
+            sessions[0].advance(PlayerControl::default()).unwrap();
+        }
+        assert_eq!(sessions[0].sim().players()[1].position, Vec2::new(7.0, 7.0));
+
+        // Player 1 was moving all along, so player 0 has to roll back
+        for _ in 0..5 {
+            sessions[1].advance(left).unwrap();
+        }
+        for _ in 0..30 {
+            sessions[1].advance(left);
+            std::thread::sleep(std::time::Duration::from_millis(1));
+            sessions[0].advance(PlayerControl::default());
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        let [a, b] = [sessions[0].sim(), sessions[1].sim()];
+        assert_eq!(a.tick(), b.tick());
+        assert!(a.players()[1].position.x < 7.0);
+        for (pa, pb) in a.players().iter().zip(b.players()) {
+            assert_eq!(pa.position, pb.position);
+        }
+        // The checksums exchanged for the confirmed ticks agree
+        assert!(sessions.iter().all(|session| !session.is_desynced()));
+    }
+}
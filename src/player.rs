@@ -1,56 +1,185 @@
+use crate::bot::BotDifficulty;
+use crate::rules::Ruleset;
+use crate::sim::{CURSED_SPEED, SHORT_FUSE};
+use crate::tile::PowerUpType;
 use bevy::prelude::*;
+use strum_macros::EnumIter;
 
-const STARTING_BOMBS: i32 = 1;
-const STARTING_FIREPOWER: i32 = 1;
-
-#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Default)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug, Default)]
 pub enum PlayerController {
     #[default]
     KeyboardArrows,
     KeyboardWASD,
     Gamepad(usize),
+    // The player at this index of an online match
+    Online(usize),
+    // A computer-controlled player
+    Bot(BotDifficulty),
+    // An agent at this index of a `gym::GymEnv`, driven by its caller
+    Agent(usize),
 }
 
-#[derive(Component)]
-pub struct Alive {
-}
-
-#[derive(Component)]
+#[derive(Clone, Debug)]
 pub struct Player {
     pub player_index: usize,
     pub controller: PlayerController,
     pub num_bombs: i32,
     pub firepower: i32,
+    pub can_kick: bool,
+    pub can_throw: bool,
+    pub has_remote: bool,
+    pub has_pierce: bool,
+    pub has_line_bomb: bool,
+    pub curse: Option<ActiveCurse>,
+    // Hits that can be soaked up, see `PowerUpType::Shield`
+    pub shields: u32,
+    // Ticks left during which explosions don't hurt
+    pub invulnerable: u32,
+    // Every power-up picked up this round, in order
+    pub power_ups: Vec<PowerUpType>,
+    // Bombs laid this round, which numbers remote bombs in the order they
+    // were laid
+    pub bombs_placed: u64,
+    // In tiles per second
+    pub speed: f32,
+    pub position: Vec2,
+    // Direction of the last motion, used to orient the model
+    pub facing: Vec2,
+    pub is_moving: bool,
+    pub alive: bool,
 }
 
 impl Player {
+    // A player with the classic starting stats, until `reset` gives them
+    // those of the rules being played
     pub fn new(player_index: usize, controller: PlayerController) -> Self {
+        let rules = Ruleset::classic();
         Self {
             player_index,
             controller,
-            num_bombs: STARTING_BOMBS,
-            firepower: STARTING_FIREPOWER,
+            num_bombs: rules.starting_bombs,
+            firepower: rules.starting_firepower,
+            can_kick: false,
+            can_throw: false,
+            has_remote: false,
+            has_pierce: false,
+            has_line_bomb: false,
+            curse: None,
+            shields: 0,
+            invulnerable: 0,
+            power_ups: Vec::new(),
+            bombs_placed: 0,
+            speed: rules.player_speed,
+            position: Vec2::ZERO,
+            facing: Vec2::Y,
+            is_moving: false,
+            alive: true,
+        }
+    }
+
+    pub fn reset(&mut self, rules: &Ruleset) {
+        self.num_bombs = rules.starting_bombs;
+        self.firepower = rules.starting_firepower;
+        self.can_kick = false;
+        self.can_throw = false;
+        self.has_remote = false;
+        self.has_pierce = false;
+        self.has_line_bomb = false;
+        self.curse = None;
+        self.shields = 0;
+        self.invulnerable = 0;
+        self.power_ups.clear();
+        self.bombs_placed = 0;
+        self.speed = rules.player_speed;
+        self.facing = Vec2::Y;
+        self.is_moving = false;
+        self.alive = true;
+    }
+
+    pub fn has_curse(&self, curse: Curse) -> bool {
+        self.curse.is_some_and(|active| active.curse == curse)
+    }
+
+    // In tiles per second, after any curse
+    pub fn current_speed(&self) -> f32 {
+        if self.has_curse(Curse::Slow) {
+            CURSED_SPEED
+        } else {
+            self.speed
         }
     }
 
-    pub fn reset(&mut self) {
-        self.num_bombs = STARTING_BOMBS;
-        self.firepower = STARTING_FIREPOWER;
+    // Ticks until the bombs the player drops now explode
+    pub fn bomb_fuse(&self, rules: &Ruleset) -> u32 {
+        if self.has_curse(Curse::ShortFuse) {
+            SHORT_FUSE.min(rules.bomb_fuse)
+        } else {
+            rules.bomb_fuse
+        }
     }
+
+    // The control the player gives after any curse has twisted it
+    pub fn cursed_control(&self, control: PlayerControl) -> PlayerControl {
+        let mut control = control;
+        match self.curse.map(|active| active.curse) {
+            Some(Curse::Reversed) => control.motion = -control.motion,
+            Some(Curse::NonstopBombs) if control.action == PlayerAction::None => {
+                control.action = PlayerAction::DropBomb
+            }
+            Some(Curse::NoBombs) if control.action == PlayerAction::DropBomb => {
+                control.action = PlayerAction::None
+            }
+            _ => {}
+        }
+        control
+    }
+}
+
+// What a skull does to the player who picks it up, for a while
+#[derive(Clone, Copy, PartialEq, Eq, Hash, EnumIter, Debug)]
+pub enum Curse {
+    // Drops a bomb whenever possible
+    NonstopBombs,
+    // Motion goes the opposite way
+    Reversed,
+    Slow,
+    // Bombs explode sooner
+    ShortFuse,
+    NoBombs,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct ActiveCurse {
+    pub curse: Curse,
+    // Ticks until it wears off
+    pub remaining: u32,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum PlayerAction {
     None,
     DropBomb,
+    // The second button, which throws a bomb with the glove
+    Throw,
+    // Sets off the oldest remote bomb
+    Detonate,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub struct PlayerControl {
     pub motion: Vec2,
     pub action: PlayerAction,
 }
 
+impl Default for PlayerControl {
+    fn default() -> Self {
+        Self {
+            motion: Vec2::ZERO,
+            action: PlayerAction::None,
+        }
+    }
+}
+
 impl PlayerControl {
     pub fn is_something(&self) -> bool {
         self.motion != Vec2::ZERO || self.action != PlayerAction::None
@@ -0,0 +1,470 @@
+use crate::bot::BotDifficulty;
+use crate::checksum::{Difference, StateDigest};
+use crate::errors::invalid_data;
+use crate::player::*;
+use crate::rules::Ruleset;
+use bevy::prelude::*;
+use std::collections::BTreeMap;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+const REPLAY_MAGIC: &[u8; 4] = b"KCRP";
+// Older replays don't record the rules they were played by, which have changed
+// since, so their maps can't be made again and they aren't played back
+const REPLAY_VERSION: u8 = 3;
+
+pub type TickInputs = BTreeMap<PlayerController, PlayerControl>;
+
+// The recorded game state at the start of a tick, as the digest elements
+// that changed since the previous tick
+#[derive(Clone, Debug, Default)]
+struct TickState {
+    checksum: u64,
+    num_elements: usize,
+    changes: Vec<(usize, u32)>,
+}
+
+// Everything needed to play a match again: the map seed, the rules it was
+// played with and the input of every controller on every tick.
+#[derive(Clone, Debug, Default)]
+pub struct Replay {
+    pub seed: u64,
+    pub ticks_per_second: u32,
+    pub rules: Ruleset,
+    // Controllers of the players present at the start, in player order
+    pub controllers: Vec<PlayerController>,
+    ticks: Vec<TickInputs>,
+    states: Vec<TickState>,
+    // The digest of the last recorded tick, to find what changed
+    last_digest: StateDigest,
+}
+
+impl Replay {
+    pub fn new(
+        seed: u64,
+        ticks_per_second: u32,
+        rules: Ruleset,
+        controllers: Vec<PlayerController>,
+    ) -> Self {
+        Self {
+            seed,
+            ticks_per_second,
+            rules,
+            controllers,
+            ticks: Vec::new(),
+            states: Vec::new(),
+            last_digest: StateDigest::default(),
+        }
+    }
+
+    pub fn num_ticks(&self) -> usize {
+        self.ticks.len()
+    }
+
+    // Records the inputs applied on a tick and the state they were applied to
+    pub fn record_tick(&mut self, inputs: &TickInputs, digest: &StateDigest) {
+        let previous = &self.last_digest.elements;
+        let changes = digest
+            .elements
+            .iter()
+            .enumerate()
+            .filter(|(index, element)| previous.get(*index) != Some(*element))
+            .map(|(index, element)| (index, *element))
+            .collect();
+
+        self.ticks.push(inputs.clone());
+        self.states.push(TickState {
+            checksum: digest.checksum(),
+            num_elements: digest.elements.len(),
+            changes,
+        });
+        self.last_digest = digest.clone();
+    }
+
+    // Whether the state of every tick was recorded and can be checked
+    pub fn has_checksums(&self) -> bool {
+        !self.ticks.is_empty() && self.states.len() == self.ticks.len()
+    }
+
+    pub fn inputs_at(&self, tick: u64) -> Option<&TickInputs> {
+        self.ticks.get(tick as usize)
+    }
+
+    // Inputs are stored as the changes from the previous tick, since most
+    // controls are held for many ticks at a time.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(REPLAY_MAGIC);
+        bytes.push(REPLAY_VERSION);
+        bytes.extend_from_slice(&self.seed.to_le_bytes());
+        bytes.extend_from_slice(&(self.ticks_per_second as u16).to_le_bytes());
+        let rules = self.rules.to_ron();
+        bytes.extend_from_slice(&(rules.len() as u32).to_le_bytes());
+        bytes.extend_from_slice(rules.as_bytes());
+
+        bytes.push(self.controllers.len() as u8);
+        for controller in &self.controllers {
+            write_controller(&mut bytes, *controller);
+        }
+
+        bytes.extend_from_slice(&(self.ticks.len() as u32).to_le_bytes());
+        let mut previous = TickInputs::new();
+        for inputs in &self.ticks {
+            let changes: Vec<_> = inputs
+                .iter()
+                .filter(|(controller, control)| {
+                    !previous
+                        .get(controller)
+                        .is_some_and(|prev| same_control(prev, control))
+                })
+                .collect();
+
+            bytes.push(changes.len() as u8);
+            for (controller, control) in changes {
+                write_controller(&mut bytes, *controller);
+                bytes.extend_from_slice(&control.motion.x.to_le_bytes());
+                bytes.extend_from_slice(&control.motion.y.to_le_bytes());
+                bytes.push(action_to_byte(control.action));
+            }
+            previous = inputs.clone();
+        }
+
+        bytes.extend_from_slice(&(self.states.len() as u32).to_le_bytes());
+        for state in &self.states {
+            bytes.extend_from_slice(&state.checksum.to_le_bytes());
+            bytes.extend_from_slice(&(state.num_elements as u16).to_le_bytes());
+            bytes.extend_from_slice(&(state.changes.len() as u16).to_le_bytes());
+            for (index, element) in &state.changes {
+                bytes.extend_from_slice(&(*index as u16).to_le_bytes());
+                bytes.extend_from_slice(&element.to_le_bytes());
+            }
+        }
+
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> std::io::Result<Self> {
+        let mut reader = ByteReader::new(bytes);
+
+        if reader.take(4)? != REPLAY_MAGIC {
+            return Err(invalid_data("not a replay file"));
+        }
+        let version = reader.u8()?;
+        if version != REPLAY_VERSION {
+            return Err(invalid_data(&format!(
+                "unsupported replay version {}",
+                version
+            )));
+        }
+
+        let mut replay = Replay::new(
+            reader.u64()?,
+            reader.u16()? as u32,
+            Ruleset::default(),
+            Vec::new(),
+        );
+        let len = reader.u32()? as usize;
+        let rules = std::str::from_utf8(reader.take(len)?)
+            .map_err(|_| invalid_data("ruleset is not text"))?;
+        replay.rules = Ruleset::from_ron(rules)?;
+
+        for _ in 0..reader.u8()? {
+            replay.controllers.push(reader.controller()?);
+        }
+
+        let num_ticks = reader.u32()?;
+        let mut inputs = TickInputs::new();
+        for _ in 0..num_ticks {
+            for _ in 0..reader.u8()? {
+                let controller = reader.controller()?;
+                let motion = Vec2::new(reader.f32()?, reader.f32()?);
+                let action = action_from_byte(reader.u8()?)?;
+                inputs.insert(controller, PlayerControl { motion, action });
+            }
+            replay.ticks.push(inputs.clone());
+        }
+
+        for _ in 0..reader.u32()? {
+            let mut state = TickState {
+                checksum: reader.u64()?,
+                num_elements: reader.u16()? as usize,
+                changes: Vec::new(),
+            };
+            for _ in 0..reader.u16()? {
+                state.changes.push((reader.u16()? as usize, reader.u32()?));
+            }
+            replay.states.push(state);
+        }
+
+        Ok(replay)
+    }
+
+    pub fn save(&self, path: &Path) -> std::io::Result<()> {
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(path, self.to_bytes())
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        Self::from_bytes(&std::fs::read(path)?)
+    }
+}
+
+// Compares the state of a game played back from a replay with the recorded
+// state, tick by tick
+#[derive(Clone, Debug, Default)]
+pub struct ReplayChecker {
+    expected: StateDigest,
+    checked_ticks: usize,
+    desynced: bool,
+}
+
+impl ReplayChecker {
+    // Returns where the game first differs from the recording, once. Ticks
+    // must be checked in order from the start of the replay.
+    pub fn check(&mut self, replay: &Replay, digest: &StateDigest) -> Option<Difference> {
+        if self.desynced || digest.tick != self.checked_ticks as u64 {
+            return None;
+        }
+        let state = replay.states.get(self.checked_ticks)?;
+
+        self.expected.tick = digest.tick;
+        self.expected.map_width = replay.rules.map_width;
+        self.expected.map_height = replay.rules.map_height;
+        self.expected.elements.resize(state.num_elements, 0);
+        for (index, element) in &state.changes {
+            if let Some(expected) = self.expected.elements.get_mut(*index) {
+                *expected = *element;
+            }
+        }
+        self.checked_ticks += 1;
+
+        if digest.checksum() == state.checksum {
+            return None;
+        }
+        self.desynced = true;
+        Some(
+            self.expected
+                .first_difference(digest)
+                .unwrap_or(Difference::Tick),
+        )
+    }
+}
+
+// Compare bit for bit so that a replay reproduces exactly what was recorded
+fn same_control(a: &PlayerControl, b: &PlayerControl) -> bool {
+    a.motion.x.to_bits() == b.motion.x.to_bits()
+        && a.motion.y.to_bits() == b.motion.y.to_bits()
+        && a.action == b.action
+}
+
+pub(crate) fn action_to_byte(action: PlayerAction) -> u8 {
+    match action {
+        PlayerAction::None => 0,
+        PlayerAction::DropBomb => 1,
+        PlayerAction::Throw => 2,
+        PlayerAction::Detonate => 3,
+    }
+}
+
+pub(crate) fn action_from_byte(byte: u8) -> std::io::Result<PlayerAction> {
+    match byte {
+        0 => Ok(PlayerAction::None),
+        1 => Ok(PlayerAction::DropBomb),
+        2 => Ok(PlayerAction::Throw),
+        3 => Ok(PlayerAction::Detonate),
+        _ => Err(invalid_data(&format!("unknown action {}", byte))),
+    }
+}
+
+fn write_controller(bytes: &mut Vec<u8>, controller: PlayerController) {
+    match controller {
+        PlayerController::KeyboardArrows => bytes.push(0),
+        PlayerController::KeyboardWASD => bytes.push(1),
+        PlayerController::Gamepad(id) => {
+            bytes.push(2);
+            bytes.extend_from_slice(&(id as u32).to_le_bytes());
+        }
+        PlayerController::Online(player_index) => {
+            bytes.push(3);
+            bytes.push(player_index as u8);
+        }
+        PlayerController::Bot(difficulty) => {
+            bytes.push(4);
+            bytes.push(difficulty as u8);
+        }
+        PlayerController::Agent(agent) => {
+            bytes.push(5);
+            bytes.push(agent as u8);
+        }
+    }
+}
+
+pub(crate) struct ByteReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ByteReader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Self {
+        Self { bytes, pos: 0 }
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> std::io::Result<&'a [u8]> {
+        let end = self.pos + len;
+        if end > self.bytes.len() {
+            return Err(Error::new(ErrorKind::UnexpectedEof, "data is truncated"));
+        }
+        let slice = &self.bytes[self.pos..end];
+        self.pos = end;
+        Ok(slice)
+    }
+
+    pub(crate) fn u8(&mut self) -> std::io::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u16(&mut self) -> std::io::Result<u16> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u32(&mut self) -> std::io::Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn u64(&mut self) -> std::io::Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub(crate) fn f32(&mut self) -> std::io::Result<f32> {
+        Ok(f32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn controller(&mut self) -> std::io::Result<PlayerController> {
+        match self.u8()? {
+            0 => Ok(PlayerController::KeyboardArrows),
+            1 => Ok(PlayerController::KeyboardWASD),
+            2 => Ok(PlayerController::Gamepad(self.u32()? as usize)),
+            3 => Ok(PlayerController::Online(self.u8()? as usize)),
+            4 => match self.u8()? {
+                0 => Ok(PlayerController::Bot(BotDifficulty::Easy)),
+                1 => Ok(PlayerController::Bot(BotDifficulty::Normal)),
+                2 => Ok(PlayerController::Bot(BotDifficulty::Hard)),
+                difficulty => Err(invalid_data(&format!(
+                    "unknown bot difficulty {}",
+                    difficulty
+                ))),
+            },
+            5 => Ok(PlayerController::Agent(self.u8()? as usize)),
+            tag => Err(invalid_data(&format!("unknown controller {}", tag))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic::*;
+    use crate::map::MapSeed;
+    use crate::sim::GameSim;
+    use crate::tile::*;
+
+    #[test]
+    fn test_replay_round_trip() {
+        let rules = Ruleset {
+            map_width: 19,
+            map_height: 13,
+            ..Ruleset::chaos()
+        };
+        let mut replay = Replay::new(
+            1234,
+            60,
+            rules.clone(),
+            vec![
+                PlayerController::KeyboardArrows,
+                PlayerController::Gamepad(3),
+            ],
+        );
+
+        let idle = PlayerControl::default();
+        let bomb = PlayerControl {
+            motion: Vec2::new(0.25, -1.0),
+            action: PlayerAction::DropBomb,
+        };
+        for tick in 0..100 {
+            let mut inputs = TickInputs::new();
+            inputs.insert(
+                PlayerController::KeyboardArrows,
+                if tick % 7 == 0 { bomb } else { idle },
+            );
+            inputs.insert(PlayerController::Gamepad(3), idle);
+            replay.record_tick(&inputs, &StateDigest::default());
+        }
+
+        let loaded = Replay::from_bytes(&replay.to_bytes()).unwrap();
+        assert_eq!(loaded.seed, 1234);
+        assert_eq!(loaded.rules, rules);
+        assert_eq!(loaded.controllers, replay.controllers);
+        assert_eq!(loaded.num_ticks(), 100);
+        for tick in 0..100 {
+            let expected = replay.inputs_at(tick).unwrap();
+            let actual = loaded.inputs_at(tick).unwrap();
+            assert_eq!(expected.len(), actual.len());
+            for (controller, control) in expected {
+                assert!(same_control(control, &actual[controller]));
+            }
+        }
+
+        assert!(Replay::from_bytes(b"nope").is_err());
+    }
+
+    fn new_sim() -> GameSim {
+        let mut rng = MapSeed(5).rng();
+        let mut sim = GameSim::new(make_basic_map(MAP_DIMENSIONS.0, MAP_DIMENSIONS.1, &mut rng));
+        sim.add_player(PlayerController::KeyboardArrows).unwrap();
+        sim
+    }
+
+    #[test]
+    fn test_replay_checker_finds_desync() {
+        let controller = PlayerController::KeyboardArrows;
+        let mut sim = new_sim();
+        let mut replay = Replay::new(5, 60, Ruleset::default(), vec![controller]);
+        for tick in 0..60 {
+            let control = PlayerControl {
+                motion: if tick < 30 { Vec2::X } else { Vec2::Y },
+                action: PlayerAction::None,
+            };
+            replay.record_tick(
+                &TickInputs::from([(controller, control)]),
+                &StateDigest::of(&sim),
+            );
+            sim.step(&[control]);
+        }
+        let replay = Replay::from_bytes(&replay.to_bytes()).unwrap();
+        assert!(replay.has_checksums());
+
+        // Playing back the same inputs matches the recording
+        let play = |sim: &mut GameSim, checker: &mut ReplayChecker| {
+            let mut first_difference = None;
+            while let Some(inputs) = replay.inputs_at(sim.tick()) {
+                if let Some(difference) = checker.check(&replay, &StateDigest::of(sim)) {
+                    first_difference.get_or_insert(difference);
+                }
+                sim.step(&[inputs[&controller]]);
+            }
+            first_difference
+        };
+        assert_eq!(play(&mut new_sim(), &mut ReplayChecker::default()), None);
+
+        // A game that started differently is caught on the first tick
+        let mut other = new_sim();
+        let pos = IVec2::new(5, 5);
+        other.tiles_mut()[pos] = TileType::PowerUp(PowerUpType::ExtraBomb);
+        assert_eq!(
+            play(&mut other, &mut ReplayChecker::default()),
+            Some(Difference::Tile(pos))
+        );
+    }
+}
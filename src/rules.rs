@@ -0,0 +1,312 @@
+use crate::basic::MAP_DIMENSIONS;
+use crate::errors::invalid_data;
+use crate::sim::{
+    BOMB_EXPLOSION_DELAY, EXPLOSION_DURATION, FREE_SPACE_BORDER, MAX_SUBSTEP_MOTION, PLAYER_SPEED,
+    TICKS_PER_SECOND,
+};
+use crate::tile::{PowerUpType, TILE_SIZE};
+use bevy::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+// The built-in rulesets, see `Ruleset::preset`. Each is also shipped as
+// `assets/rules/<name>.ron`, as a starting point for rulesets of your own.
+pub const PRESET_NAMES: [&str; 3] = ["classic", "chaos", "speed"];
+
+// The range of map sizes that maps are generated and sent over the network at
+const MAP_DIMENSION_RANGE: std::ops::RangeInclusive<usize> = 5..=255;
+// Slower players would take so long to cross a tile that bots can't plan for
+// them
+const MIN_PLAYER_SPEED: f32 = 0.5 * TILE_SIZE;
+// The longest a fuse, blast or wait for sudden death may last
+const MAX_TIMER: u32 = 10 * 60 * TICKS_PER_SECOND;
+
+// The numbers a match is played with. They can be changed without a rebuild
+// by loading them from a RON file, in which any field left out keeps its
+// classic value, e.g.
+//
+//   (
+//       name: "Big bangs",
+//       starting_firepower: 3,
+//       power_ups: [(ExtraBomb, 0.1), (Kick, 0.05)],
+//   )
+#[derive(Resource, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Ruleset {
+    pub name: String,
+    pub map_width: usize,
+    pub map_height: usize,
+    // Chance that an open tile away from the spawn points starts as a
+    // breakable wall
+    pub breakable_walls: f32,
+    // Chance that a breakable wall hides each power-up, tried in order.
+    // Power-ups that aren't listed are disabled and never appear.
+    pub power_ups: Vec<(PowerUpType, f32)>,
+    // Exact number of each power-up to hide in the breakable walls of a map,
+    // which replaces the chances above when not empty
+    pub power_up_counts: Vec<(PowerUpType, u32)>,
+    // Deal the counted power-ups out evenly between the quarters of the map,
+    // so that each corner spawn point has its share nearby
+    pub balance_quadrants: bool,
+    pub starting_bombs: i32,
+    pub starting_firepower: i32,
+    // The most that power-ups can raise them to
+    pub max_bombs: i32,
+    pub max_firepower: i32,
+    // In tiles per second
+    pub player_speed: f32,
+    pub max_player_speed: f32,
+    // How close to a wall players can get, in tiles
+    pub free_space_border: f32,
+    // Ticks from dropping a bomb until it explodes
+    pub bomb_fuse: u32,
+    // Ticks that a blast burns for
+    pub explosion_duration: u32,
+    // Ticks into a round before walls start closing in from the edges, or
+    // `None` to let a round go on for ever
+    pub sudden_death: Option<u32>,
+    // Ticks between one closing wall landing and the next
+    pub sudden_death_interval: u32,
+}
+
+impl Default for Ruleset {
+    fn default() -> Self {
+        Self::classic()
+    }
+}
+
+impl Ruleset {
+    pub fn classic() -> Self {
+        Self {
+            name: "Classic".to_string(),
+            map_width: MAP_DIMENSIONS.0,
+            map_height: MAP_DIMENSIONS.1,
+            breakable_walls: 0.8,
+            power_ups: vec![
+                (PowerUpType::Firepower, 0.08),
+                (PowerUpType::ExtraBomb, 0.08),
+                (PowerUpType::Kick, 0.04),
+                (PowerUpType::Glove, 0.04),
+                (PowerUpType::Remote, 0.03),
+                (PowerUpType::Speed, 0.06),
+                (PowerUpType::Pierce, 0.03),
+                (PowerUpType::LineBomb, 0.03),
+                (PowerUpType::Skull, 0.03),
+                (PowerUpType::Shield, 0.02),
+            ],
+            power_up_counts: Vec::new(),
+            balance_quadrants: false,
+            starting_bombs: 1,
+            starting_firepower: 1,
+            max_bombs: 10,
+            max_firepower: 10,
+            player_speed: PLAYER_SPEED,
+            max_player_speed: 6.0 * TILE_SIZE,
+            free_space_border: FREE_SPACE_BORDER,
+            bomb_fuse: BOMB_EXPLOSION_DELAY,
+            explosion_duration: EXPLOSION_DURATION,
+            sudden_death: None,
+            sudden_death_interval: TICKS_PER_SECOND / 4,
+        }
+    }
+
+    // Packed walls hiding plenty of everything, and players who start strong
+    pub fn chaos() -> Self {
+        let classic = Self::classic();
+        Self {
+            name: "Chaos".to_string(),
+            breakable_walls: 0.9,
+            power_ups: classic
+                .power_ups
+                .iter()
+                .map(|(power_up, chance)| (*power_up, chance * 2.0))
+                .collect(),
+            starting_bombs: 3,
+            starting_firepower: 3,
+            max_bombs: 12,
+            max_firepower: 12,
+            ..classic
+        }
+    }
+
+    // Fast players and short fuses, with fewer walls in the way
+    pub fn speed() -> Self {
+        Self {
+            name: "Speed".to_string(),
+            breakable_walls: 0.6,
+            player_speed: 4.5 * TILE_SIZE,
+            max_player_speed: 8.0 * TILE_SIZE,
+            bomb_fuse: 2 * TICKS_PER_SECOND,
+            sudden_death: Some(60 * TICKS_PER_SECOND),
+            ..Self::classic()
+        }
+    }
+
+    // One of `PRESET_NAMES`, ignoring case
+    pub fn preset(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "classic" => Some(Self::classic()),
+            "chaos" => Some(Self::chaos()),
+            "speed" => Some(Self::speed()),
+            _ => None,
+        }
+    }
+
+    pub fn is_enabled(&self, power_up: PowerUpType) -> bool {
+        if self.power_up_counts.is_empty() {
+            self.power_ups
+                .iter()
+                .any(|(enabled, _)| *enabled == power_up)
+        } else {
+            self.power_up_counts
+                .iter()
+                .any(|(enabled, count)| *enabled == power_up && *count > 0)
+        }
+    }
+
+    pub fn from_ron(text: &str) -> std::io::Result<Self> {
+        let rules: Self = ron::from_str(text).map_err(|err| invalid_data(&err.to_string()))?;
+        rules.validate()?;
+        Ok(rules)
+    }
+
+    // Rejects numbers the game can't be played with
+    pub fn validate(&self) -> std::io::Result<()> {
+        let check = |valid: bool, message: &str| {
+            if valid {
+                Ok(())
+            } else {
+                Err(invalid_data(message))
+            }
+        };
+        let is_chance = |chance: f32| (0.0..=1.0).contains(&chance);
+        let is_timer = |ticks: u32| (1..=MAX_TIMER).contains(&ticks);
+
+        check(
+            MAP_DIMENSION_RANGE.contains(&self.map_width)
+                && MAP_DIMENSION_RANGE.contains(&self.map_height),
+            &format!(
+                "the map must be between {} and {} tiles across",
+                MAP_DIMENSION_RANGE.start(),
+                MAP_DIMENSION_RANGE.end()
+            ),
+        )?;
+        check(
+            is_chance(self.breakable_walls)
+                && self.power_ups.iter().all(|(_, chance)| is_chance(*chance)),
+            "chances must be between 0 and 1",
+        )?;
+        check(
+            self.starting_bombs >= 1 && self.max_bombs >= self.starting_bombs,
+            "players need a bomb, and at most `max_bombs`",
+        )?;
+        check(
+            self.starting_firepower >= 1 && self.max_firepower >= self.starting_firepower,
+            "players need firepower, and at most `max_firepower`",
+        )?;
+        check(
+            self.player_speed >= MIN_PLAYER_SPEED
+                && self.player_speed.is_finite()
+                && self.max_player_speed >= self.player_speed
+                && self.max_player_speed.is_finite(),
+            &format!(
+                "players need a speed of at least {}, and at most `max_player_speed`",
+                MIN_PLAYER_SPEED
+            ),
+        )?;
+        check(
+            self.free_space_border > MAX_SUBSTEP_MOTION && self.free_space_border < 0.5,
+            &format!(
+                "`free_space_border` must be between {} and 0.5",
+                MAX_SUBSTEP_MOTION
+            ),
+        )?;
+        check(
+            is_timer(self.bomb_fuse)
+                && is_timer(self.explosion_duration)
+                && is_timer(self.sudden_death_interval)
+                && self.sudden_death.is_none_or(|start| start <= MAX_TIMER),
+            &format!("timers must last between 1 and {} ticks", MAX_TIMER),
+        )
+    }
+
+    pub fn to_ron(&self) -> String {
+        ron::ser::to_string_pretty(self, ron::ser::PrettyConfig::default())
+            .expect("a ruleset is always representable in RON")
+    }
+
+    pub fn load(path: &Path) -> std::io::Result<Self> {
+        Self::from_ron(&std::fs::read_to_string(path)?)
+    }
+
+    // A preset given by name, or else a ruleset file given by path
+    pub fn from_preset_or_file(name_or_path: &str) -> std::io::Result<Self> {
+        match Self::preset(name_or_path) {
+            Some(rules) => Ok(rules),
+            None => Self::load(Path::new(name_or_path)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules_from_ron() {
+        let rules =
+            Ruleset::from_ron("(name: \"League\", starting_bombs: 2, power_ups: [(Kick, 0.5)])")
+                .unwrap();
+        assert_eq!(rules.name, "League");
+        assert_eq!(rules.starting_bombs, 2);
+        assert_eq!(
+            rules.starting_firepower,
+            Ruleset::classic().starting_firepower
+        );
+        assert!(rules.is_enabled(PowerUpType::Kick));
+        assert!(!rules.is_enabled(PowerUpType::Firepower));
+        assert!(Ruleset::from_ron("(starting_bombs: \"two\")").is_err());
+
+        // Numbers that can't be played with are turned away
+        for invalid in [
+            "(player_speed: 0.0)",
+            "(map_width: 2)",
+            "(map_height: 0)",
+            "(bomb_fuse: 0)",
+            "(sudden_death_interval: 0)",
+            "(breakable_walls: 1.5)",
+            "(starting_bombs: 0)",
+            "(max_firepower: 0)",
+            "(free_space_border: 0.0)",
+        ] {
+            assert!(
+                Ruleset::from_ron(invalid).is_err(),
+                "{} was accepted",
+                invalid
+            );
+        }
+
+        // Counts take over from the chances
+        let counted = Ruleset::from_ron("(power_up_counts: [(Kick, 2), (Glove, 0)])").unwrap();
+        assert!(counted.is_enabled(PowerUpType::Kick));
+        assert!(!counted.is_enabled(PowerUpType::Glove));
+        assert!(!counted.is_enabled(PowerUpType::Firepower));
+
+        for name in PRESET_NAMES {
+            let preset = Ruleset::preset(name).unwrap();
+            assert_eq!(Ruleset::from_ron(&preset.to_ron()).unwrap(), preset);
+        }
+        assert_ne!(Ruleset::preset("Chaos"), Ruleset::preset("classic"));
+
+        // The shipped preset files are kept the same as the built-in presets
+        for name in PRESET_NAMES {
+            let path = format!("{}/assets/rules/{}.ron", env!("CARGO_MANIFEST_DIR"), name);
+            assert_eq!(
+                Ruleset::load(Path::new(&path)).unwrap(),
+                Ruleset::preset(name).unwrap(),
+                "{} is out of date",
+                path
+            );
+        }
+    }
+}
@@ -1,10 +1,10 @@
 use bevy::prelude::*;
 use zerocopy::AsBytes;
-use crate::map::Map;
+use crate::map::TileGrid;
 use crate::tile::*;
 
-fn is_walkable(tile: &Tile) -> bool {
-  matches!(tile.tile_type, TileType::Empty | TileType::PowerUp(_))
+pub fn is_walkable(tile_type: &TileType) -> bool {
+  matches!(tile_type, TileType::Empty | TileType::PowerUp(_))
 }
 
 pub fn closest_dist_to_tile(pos_in_map: Vec2, map_tile_index: IVec2) -> f32 {
@@ -17,11 +17,11 @@ pub fn closest_dist_to_tile(pos_in_map: Vec2, map_tile_index: IVec2) -> f32 {
 
 // The extra_free_in_map is a bit of a hack to handle the case where the player
 // is standing on a tile containing their own bomb that they have just placed.
-pub fn map_sdf(map: &Map, pos_in_map: Vec2, tiles: &Query<&Tile>, extra_free_in_map: Option<IVec2>) -> (f32, Vec2) {
+pub fn map_sdf(map: &TileGrid, pos_in_map: Vec2, extra_free_in_map: Option<IVec2>) -> (f32, Vec2) {
   let center_index = map.get_index_from_position(pos_in_map).unwrap_or_default();
   let rad = 2; // Adjust this radius as needed
 
-  let center_tile = tiles.get(map[center_index]).ok();
+  let center_tile = map.contains(center_index).then(|| &map[center_index]);
   let is_in_empty = center_tile.map_or(true, |t| is_walkable(t) || (extra_free_in_map.is_some_and(|extra_free| extra_free == center_index)));
 
   let (sdf, closest_point) = (-rad..=rad)
@@ -29,15 +29,13 @@ pub fn map_sdf(map: &Map, pos_in_map: Vec2, tiles: &Query<&Tile>, extra_free_in_
       .filter_map(|offset| {
           let index = center_index + offset;
           if map.contains(index) {
-              tiles.get(map[index]).ok().and_then(|tile| {
-                  let is_empty = is_walkable(tile) || (extra_free_in_map.is_some_and(|extra_free| extra_free == index));
-                  if is_empty != is_in_empty {
-                      let dist = closest_dist_to_tile(pos_in_map, index);
-                      Some((dist, index.as_vec2()))
-                  } else {
-                      None
-                  }
-              })
+              let is_empty = is_walkable(&map[index]) || (extra_free_in_map.is_some_and(|extra_free| extra_free == index));
+              if is_empty != is_in_empty {
+                  let dist = closest_dist_to_tile(pos_in_map, index);
+                  Some((dist, index.as_vec2()))
+              } else {
+                  None
+              }
           } else {
               None
           }
@@ -70,7 +68,7 @@ pub fn jet_colormap(sdf: f32) -> Color {
   }
 }
 
-pub fn map_sdf_image(map: &Map, tiles: &Query<&Tile>) -> Image {
+pub fn map_sdf_image(map: &TileGrid) -> Image {
   let scale = 40;
   let width = (map.width() * scale) as u32;
   let height = (map.height() * scale) as u32;
@@ -80,7 +78,7 @@ pub fn map_sdf_image(map: &Map, tiles: &Query<&Tile>) -> Image {
   for y in 0..height {
       for x in 0..width {
           let pos = Vec2::new(x as f32 / scale as f32 - 0.5, y as f32 / scale as f32 - 0.5);
-          let sdf = map_sdf(map, pos, tiles, None);
+          let sdf = map_sdf(map, pos, None);
           let idx = (y * width + x) as usize;
           sdfs[idx] = sdf.0;
       }
@@ -130,10 +128,9 @@ pub fn map_sdf_image(map: &Map, tiles: &Query<&Tile>) -> Image {
 pub fn spawn_sdf_texture(
   commands: &mut Commands,
   images: &mut ResMut<Assets<Image>>,
-  map: &Map,
-  tiles: &Query<&Tile>,
+  map: &TileGrid,
 ) {
-  let img = map_sdf_image(map, tiles);
+  let img = map_sdf_image(map);
   let texture_handle = images.add(img);
 
   // Create a new camera for 2D overlay
@@ -0,0 +1,739 @@
+use crate::basic::*;
+use crate::errors::invalid_data;
+use crate::map::{MapSeed, TileGrid};
+use crate::player::*;
+use crate::replay::{action_from_byte, action_to_byte, ByteReader};
+use crate::rules::Ruleset;
+use crate::sim::*;
+use crate::tile::*;
+use bevy::prelude::*;
+use std::io::ErrorKind;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use strum::IntoEnumIterator;
+
+const PACKET_MAGIC: &[u8; 4] = b"KCSV";
+
+// A client that sends nothing for this long has left, freeing its player
+const CLIENT_TIMEOUT_TICKS: u64 = 5 * TICKS_PER_SECOND as u64;
+
+// How long the result of a round is shown before the next one starts
+const ROUND_RESTART_TICKS: u32 = 3 * TICKS_PER_SECOND;
+
+// The most a UDP datagram can carry, which is as big as a snapshot can get
+const MAX_SNAPSHOT_LEN: usize = 65507;
+
+// Everything a client needs to draw the game at one tick
+pub struct Snapshot {
+    pub round: u32,
+    pub seed: u64,
+    pub sim: GameSim,
+    // Only events the client can react to, currently explosions
+    pub events: Vec<SimEvent>,
+}
+
+// Runs matches for remote clients. Clients only send their controls and draw
+// the snapshots they are sent back, so the server alone decides what happens.
+pub struct GameServer {
+    socket: UdpSocket,
+    fixed_seed: Option<u64>,
+    seed: u64,
+    round: u32,
+    sim: GameSim,
+    clients: Vec<RemoteClient>,
+    // Ticks since the server started, which unlike the round's tick never resets
+    ticks: u64,
+    // Ticks since the current round was decided
+    round_over_ticks: u32,
+}
+
+struct RemoteClient {
+    address: SocketAddr,
+    player_index: usize,
+    control: PlayerControl,
+    action_seq: u32,
+    last_heard: u64,
+}
+
+impl GameServer {
+    // Serve on `socket`, playing every round on the map from `seed` if given
+    pub fn new(socket: UdpSocket, seed: Option<u64>, rules: Ruleset) -> std::io::Result<Self> {
+        socket.set_nonblocking(true)?;
+        let mut server = Self {
+            socket,
+            fixed_seed: seed,
+            seed: 0,
+            round: 0,
+            sim: GameSim::with_rules(TileGrid::default(), rules),
+            clients: Vec::new(),
+            ticks: 0,
+            round_over_ticks: 0,
+        };
+        server.start_round();
+
+        let tiles = server.sim.tiles();
+        if max_snapshot_len(tiles, server.sim.rules()) > MAX_SNAPSHOT_LEN {
+            return Err(std::io::Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "a {}x{} map is too big to send to clients",
+                    tiles.width(),
+                    tiles.height()
+                ),
+            ));
+        }
+        Ok(server)
+    }
+
+    pub fn sim(&self) -> &GameSim {
+        &self.sim
+    }
+
+    pub fn num_clients(&self) -> usize {
+        self.clients.len()
+    }
+
+    // Read the clients' controls, step the game and send everyone the result
+    pub fn tick(&mut self) {
+        self.receive();
+
+        let ticks = self.ticks;
+        self.clients.retain(|client| {
+            let connected = ticks - client.last_heard < CLIENT_TIMEOUT_TICKS;
+            if !connected {
+                println!(
+                    "Player {} ({}) timed out",
+                    client.player_index, client.address
+                );
+            }
+            connected
+        });
+
+        let mut controls = vec![PlayerControl::default(); self.sim.players().len()];
+        for client in &mut self.clients {
+            controls[client.player_index] = client.control;
+            client.control.action = PlayerAction::None;
+        }
+
+        let events = self.sim.step(&controls);
+        if self.sim.outcome().is_some() {
+            self.round_over_ticks += 1;
+        }
+        self.send_snapshot(&events);
+
+        if self.round_over_ticks >= ROUND_RESTART_TICKS {
+            self.start_round();
+        }
+        self.ticks += 1;
+    }
+
+    fn start_round(&mut self) {
+        self.seed = self.fixed_seed.unwrap_or_else(|| MapSeed::random().0);
+        self.round = self.round.wrapping_add(1);
+        self.round_over_ticks = 0;
+        self.sim.set_seed(self.seed);
+        self.sim.restart(make_basic_map_with_rules(
+            self.sim.rules(),
+            &mut MapSeed(self.seed).rng(),
+        ));
+    }
+
+    fn receive(&mut self) {
+        let mut buf = [0u8; 256];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    eprintln!("Unable to receive from clients: {}", err);
+                    break;
+                }
+            };
+
+            if let Err(err) = self.read_packet(&buf[..len], from) {
+                eprintln!("Ignoring packet from {}: {}", from, err);
+            }
+        }
+    }
+
+    fn read_packet(&mut self, bytes: &[u8], from: SocketAddr) -> std::io::Result<()> {
+        let mut reader = ByteReader::new(bytes);
+        if reader.take(4)? != PACKET_MAGIC {
+            return Err(invalid_data("not a game packet"));
+        }
+        let motion = Vec2::new(reader.f32()?, reader.f32()?);
+        let action = action_from_byte(reader.u8()?)?;
+        let action_seq = reader.u32()?;
+
+        let client_index = match self.clients.iter().position(|c| c.address == from) {
+            Some(client_index) => client_index,
+            None => {
+                let Some(player_index) = self.free_player() else {
+                    return Err(invalid_data("the server is full"));
+                };
+                println!("Player {} joined from {}", player_index, from);
+                self.clients.push(RemoteClient {
+                    address: from,
+                    player_index,
+                    control: PlayerControl::default(),
+                    action_seq: 0,
+                    last_heard: self.ticks,
+                });
+                self.clients.len() - 1
+            }
+        };
+
+        // Clients repeat their last action until a new one replaces it, so
+        // that a lost packet doesn't lose the action
+        let client = &mut self.clients[client_index];
+        client.control.motion = motion;
+        if action_seq != client.action_seq {
+            client.action_seq = action_seq;
+            client.control.action = action;
+        }
+        client.last_heard = self.ticks;
+        Ok(())
+    }
+
+    // A player left behind by a client that has gone, or else a new one
+    fn free_player(&mut self) -> Option<usize> {
+        let taken = |player_index: usize| {
+            self.clients
+                .iter()
+                .any(|client| client.player_index == player_index)
+        };
+        match (0..self.sim.players().len()).find(|player_index| !taken(*player_index)) {
+            Some(player_index) => Some(player_index),
+            None => {
+                let player_index = self.sim.players().len();
+                self.sim.add_player(PlayerController::Online(player_index))
+            }
+        }
+    }
+
+    fn send_snapshot(&self, events: &[SimEvent]) {
+        let bytes = match snapshot_to_bytes(self.round, self.seed, &self.sim, events) {
+            Ok(bytes) => bytes,
+            Err(err) => {
+                eprintln!("Unable to send a snapshot: {}", err);
+                return;
+            }
+        };
+        for client in &self.clients {
+            if let Err(err) = self.socket.send_to(&bytes, client.address) {
+                eprintln!("Unable to send to {}: {}", client.address, err);
+            }
+        }
+    }
+}
+
+// The connection from the game to a `GameServer`
+pub struct ServerClient {
+    socket: UdpSocket,
+    server: SocketAddr,
+    last_action: PlayerAction,
+    action_seq: u32,
+    // Round and tick of the newest snapshot received
+    latest: (u32, u64),
+}
+
+impl ServerClient {
+    pub fn new(server: SocketAddr) -> std::io::Result<Self> {
+        let any_address: SocketAddr = if server.is_ipv4() {
+            "0.0.0.0:0".parse().unwrap()
+        } else {
+            "[::]:0".parse().unwrap()
+        };
+        let socket = UdpSocket::bind(any_address)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            server,
+            last_action: PlayerAction::None,
+            action_seq: 0,
+            latest: (0, 0),
+        })
+    }
+
+    // Join the server, waiting for the first snapshot to arrive
+    pub fn connect(server: SocketAddr, timeout: Duration) -> std::io::Result<(Self, Snapshot)> {
+        let mut client = Self::new(server)?;
+        let start = Instant::now();
+        while start.elapsed() < timeout {
+            client.send_control(PlayerControl::default())?;
+            std::thread::sleep(Duration::from_millis(50));
+            if let Some(snapshot) = client.receive() {
+                return Ok((client, snapshot));
+            }
+        }
+        Err(std::io::Error::new(
+            ErrorKind::TimedOut,
+            "no reply from the server",
+        ))
+    }
+
+    pub fn send_control(&mut self, control: PlayerControl) -> std::io::Result<()> {
+        if control.action != PlayerAction::None {
+            self.last_action = control.action;
+            self.action_seq = self.action_seq.wrapping_add(1);
+        }
+
+        let mut bytes = Vec::new();
+        bytes.extend_from_slice(PACKET_MAGIC);
+        bytes.extend_from_slice(&control.motion.x.to_le_bytes());
+        bytes.extend_from_slice(&control.motion.y.to_le_bytes());
+        bytes.push(action_to_byte(self.last_action));
+        bytes.extend_from_slice(&self.action_seq.to_le_bytes());
+        self.socket.send_to(&bytes, self.server)?;
+        Ok(())
+    }
+
+    // The newest snapshot that arrived since the last call, with the events of
+    // all the snapshots it replaces
+    pub fn receive(&mut self) -> Option<Snapshot> {
+        let mut buf = vec![0u8; MAX_SNAPSHOT_LEN];
+        let mut newest: Option<Snapshot> = None;
+        let mut events = Vec::new();
+
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buf) {
+                Ok(received) => received,
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    eprintln!("Unable to receive from the server: {}", err);
+                    break;
+                }
+            };
+            if from != self.server {
+                continue;
+            }
+
+            match snapshot_from_bytes(&buf[..len]) {
+                Ok(snapshot) => {
+                    // Packets may arrive out of order
+                    let order = (snapshot.round, snapshot.sim.tick());
+                    if order <= self.latest {
+                        continue;
+                    }
+                    self.latest = order;
+                    events.extend(snapshot.events.iter().copied());
+                    newest = Some(snapshot);
+                }
+                Err(err) => eprintln!("Ignoring packet from the server: {}", err),
+            }
+        }
+
+        newest.map(|snapshot| Snapshot { events, ..snapshot })
+    }
+}
+
+// The longest a snapshot of a round on `tiles` could be: every tile holding a
+// sliding bomb that goes off, a player on every spawn point holding between
+// them a power-up from every tile, and every bomb they may lay in the air
+fn max_snapshot_len(tiles: &TileGrid, rules: &Ruleset) -> usize {
+    const HEADER_LEN: usize = 32;
+    const BOMB_LEN: usize = 19;
+    const TILE_LEN: usize = 1 + BOMB_LEN;
+    const PLAYER_LEN: usize = 42;
+    const POWER_UP_LEN: usize = 1;
+    const THROWN_BOMB_LEN: usize = BOMB_LEN + 10;
+    const EXPLOSION_LEN: usize = 4;
+
+    let num_tiles = tiles.width() * tiles.height();
+    let num_players = tiles.spawn_points().len();
+    HEADER_LEN
+        + rules.to_ron().len()
+        + num_tiles * TILE_LEN
+        + 1
+        + num_players * PLAYER_LEN
+        + num_tiles * POWER_UP_LEN
+        + 2
+        + num_players * rules.max_bombs.max(0) as usize * THROWN_BOMB_LEN
+        + 2
+        + num_tiles * EXPLOSION_LEN
+}
+
+// Counts are sent in two bytes, which is more than a map small enough to send
+// can hold of anything
+fn write_count(bytes: &mut Vec<u8>, count: usize) -> std::io::Result<()> {
+    let count = u16::try_from(count)
+        .map_err(|_| invalid_data(&format!("{} is too many to send", count)))?;
+    bytes.extend_from_slice(&count.to_le_bytes());
+    Ok(())
+}
+
+fn snapshot_to_bytes(
+    round: u32,
+    seed: u64,
+    sim: &GameSim,
+    events: &[SimEvent],
+) -> std::io::Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(PACKET_MAGIC);
+    bytes.extend_from_slice(&round.to_le_bytes());
+    bytes.extend_from_slice(&seed.to_le_bytes());
+    bytes.extend_from_slice(&sim.tick().to_le_bytes());
+    // Clients play along by the same rules, e.g. to draw walls closing in
+    let rules = sim.rules().to_ron();
+    bytes.extend_from_slice(&(rules.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(rules.as_bytes());
+
+    let tiles = sim.tiles();
+    bytes.extend_from_slice(&(tiles.width() as u16).to_le_bytes());
+    bytes.extend_from_slice(&(tiles.height() as u16).to_le_bytes());
+    for pos in tiles.pos_iter() {
+        write_tile(&mut bytes, &tiles[pos]);
+    }
+
+    bytes.push(sim.players().len() as u8);
+    for player in sim.players() {
+        bytes.extend_from_slice(&player.position.x.to_le_bytes());
+        bytes.extend_from_slice(&player.position.y.to_le_bytes());
+        bytes.extend_from_slice(&player.facing.x.to_le_bytes());
+        bytes.extend_from_slice(&player.facing.y.to_le_bytes());
+        bytes.push(
+            player.is_moving as u8
+                | (player.alive as u8) << 1
+                | (player.can_kick as u8) << 2
+                | (player.can_throw as u8) << 3
+                | (player.has_remote as u8) << 4
+                | (player.has_pierce as u8) << 5
+                | (player.has_line_bomb as u8) << 6,
+        );
+        bytes.extend_from_slice(&player.num_bombs.to_le_bytes());
+        bytes.extend_from_slice(&player.firepower.to_le_bytes());
+        bytes.extend_from_slice(&player.speed.to_le_bytes());
+        match player.curse {
+            None => bytes.push(0),
+            Some(active) => {
+                bytes.push(1 + active.curse as u8);
+                bytes.extend_from_slice(&active.remaining.to_le_bytes());
+            }
+        }
+        write_count(&mut bytes, player.shields as usize)?;
+        bytes.extend_from_slice(&player.invulnerable.to_le_bytes());
+        write_count(&mut bytes, player.power_ups.len())?;
+        for power_up in &player.power_ups {
+            write_tile(&mut bytes, &TileType::PowerUp(*power_up));
+        }
+    }
+
+    write_count(&mut bytes, sim.thrown_bombs().len())?;
+    for thrown in sim.thrown_bombs() {
+        write_bomb(&mut bytes, &thrown.bomb);
+        bytes.extend_from_slice(&(thrown.from.x as u16).to_le_bytes());
+        bytes.extend_from_slice(&(thrown.from.y as u16).to_le_bytes());
+        write_direction(&mut bytes, thrown.direction);
+        bytes.push(thrown.distance as u8);
+        bytes.push(thrown.progress as u8);
+        bytes.extend_from_slice(&(thrown.travelled as u16).to_le_bytes());
+    }
+
+    let explosions: Vec<_> = events
+        .iter()
+        .filter_map(|event| match event {
+            SimEvent::BombExploded(pos) => Some(*pos),
+            _ => None,
+        })
+        .collect();
+    write_count(&mut bytes, explosions.len())?;
+    for pos in explosions {
+        bytes.extend_from_slice(&(pos.x as u16).to_le_bytes());
+        bytes.extend_from_slice(&(pos.y as u16).to_le_bytes());
+    }
+
+    Ok(bytes)
+}
+
+fn snapshot_from_bytes(bytes: &[u8]) -> std::io::Result<Snapshot> {
+    let mut reader = ByteReader::new(bytes);
+    if reader.take(4)? != PACKET_MAGIC {
+        return Err(invalid_data("not a game packet"));
+    }
+    let round = reader.u32()?;
+    let seed = reader.u64()?;
+    let tick = reader.u64()?;
+    let len = reader.u32()? as usize;
+    let rules =
+        std::str::from_utf8(reader.take(len)?).map_err(|_| invalid_data("ruleset is not text"))?;
+    let rules = Ruleset::from_ron(rules)?;
+
+    let width = reader.u16()? as usize;
+    let height = reader.u16()? as usize;
+    let mut tiles = TileGrid::filled(width, height, TileType::Empty);
+    for pos in tiles.pos_iter() {
+        tiles[pos] = read_tile(&mut reader)?;
+    }
+
+    let mut players = Vec::new();
+    for player_index in 0..reader.u8()? as usize {
+        let mut player = Player::new(player_index, PlayerController::Online(player_index));
+        player.position = Vec2::new(reader.f32()?, reader.f32()?);
+        player.facing = Vec2::new(reader.f32()?, reader.f32()?);
+        let flags = reader.u8()?;
+        player.is_moving = flags & 1 != 0;
+        player.alive = flags & 2 != 0;
+        player.can_kick = flags & 4 != 0;
+        player.can_throw = flags & 8 != 0;
+        player.has_remote = flags & 16 != 0;
+        player.has_pierce = flags & 32 != 0;
+        player.has_line_bomb = flags & 64 != 0;
+        player.num_bombs = reader.u32()? as i32;
+        player.firepower = reader.u32()? as i32;
+        player.speed = reader.f32()?;
+        player.curse = match reader.u8()? {
+            0 => None,
+            tag => {
+                let curse = Curse::iter()
+                    .nth(tag as usize - 1)
+                    .ok_or_else(|| invalid_data(&format!("unknown curse {}", tag)))?;
+                Some(ActiveCurse {
+                    curse,
+                    remaining: reader.u32()?,
+                })
+            }
+        };
+        player.shields = reader.u16()? as u32;
+        player.invulnerable = reader.u32()?;
+        for _ in 0..reader.u16()? {
+            match read_tile(&mut reader)? {
+                TileType::PowerUp(power_up) => player.power_ups.push(power_up),
+                _ => return Err(invalid_data("expected a power-up")),
+            }
+        }
+        players.push(player);
+    }
+
+    let mut thrown_bombs = Vec::new();
+    for _ in 0..reader.u16()? {
+        thrown_bombs.push(ThrownBomb {
+            bomb: read_bomb(&mut reader)?,
+            from: IVec2::new(reader.u16()? as i32, reader.u16()? as i32),
+            direction: read_direction(&mut reader)?,
+            distance: reader.u8()? as i32,
+            progress: reader.u8()? as u32,
+            travelled: reader.u16()? as i32,
+        });
+    }
+
+    let mut events = Vec::new();
+    for _ in 0..reader.u16()? {
+        let pos = IVec2::new(reader.u16()? as i32, reader.u16()? as i32);
+        events.push(SimEvent::BombExploded(pos));
+    }
+
+    let sim = GameSim::from_state(tiles, players, thrown_bombs, tick, rules, seed);
+    Ok(Snapshot {
+        round,
+        seed,
+        sim,
+        events,
+    })
+}
+
+// Walls are sent without their contents, so clients can't tell where the
+// power-ups are hidden
+fn write_tile(bytes: &mut Vec<u8>, tile: &TileType) {
+    match tile {
+        TileType::Empty => bytes.push(0),
+        TileType::SolidWall => bytes.push(1),
+        TileType::BreakableWall(_) => bytes.push(2),
+        TileType::Bomb(None) => bytes.push(3),
+        TileType::Bomb(Some(bomb)) => {
+            bytes.push(4);
+            write_bomb(bytes, bomb);
+        }
+        TileType::Explosion(remaining, _) => {
+            bytes.push(5);
+            bytes.extend_from_slice(&remaining.unwrap_or(u32::MAX).to_le_bytes());
+        }
+        TileType::PowerUp(PowerUpType::Firepower) => bytes.push(6),
+        TileType::PowerUp(PowerUpType::ExtraBomb) => bytes.push(7),
+        TileType::PowerUp(PowerUpType::Kick) => bytes.push(8),
+        TileType::PowerUp(PowerUpType::Glove) => bytes.push(9),
+        TileType::PowerUp(PowerUpType::Remote) => bytes.push(10),
+        TileType::PowerUp(PowerUpType::Speed) => bytes.push(11),
+        TileType::PowerUp(PowerUpType::Pierce) => bytes.push(12),
+        TileType::PowerUp(PowerUpType::LineBomb) => bytes.push(13),
+        TileType::PowerUp(PowerUpType::Skull) => bytes.push(14),
+        TileType::PowerUp(PowerUpType::Shield) => bytes.push(15),
+    }
+}
+
+fn write_bomb(bytes: &mut Vec<u8>, bomb: &Bomb) {
+    bytes.extend_from_slice(&bomb.fuse.to_le_bytes());
+    bytes.push(bomb.firepower as u8);
+    bytes.push(bomb.player_index as u8);
+    bytes.push(bomb.pierce as u8);
+    bytes.extend_from_slice(&bomb.remote.unwrap_or(u64::MAX).to_le_bytes());
+    match bomb.slide {
+        None => bytes.push(0),
+        Some(slide) => {
+            bytes.push(1);
+            write_direction(bytes, slide.direction);
+            bytes.push(slide.progress as u8);
+        }
+    }
+}
+
+fn read_bomb(reader: &mut ByteReader) -> std::io::Result<Bomb> {
+    let mut bomb = Bomb::new(reader.u32()?, reader.u8()? as i32, reader.u8()? as usize);
+    bomb.pierce = reader.u8()? != 0;
+    let remote = reader.u64()?;
+    bomb.remote = (remote != u64::MAX).then_some(remote);
+    if reader.u8()? != 0 {
+        bomb.slide = Some(Slide {
+            direction: read_direction(reader)?,
+            progress: reader.u8()? as u32,
+        });
+    }
+    Ok(bomb)
+}
+
+fn write_direction(bytes: &mut Vec<u8>, direction: IVec2) {
+    bytes.push(direction.x as i8 as u8);
+    bytes.push(direction.y as i8 as u8);
+}
+
+fn read_direction(reader: &mut ByteReader) -> std::io::Result<IVec2> {
+    Ok(IVec2::new(
+        reader.u8()? as i8 as i32,
+        reader.u8()? as i8 as i32,
+    ))
+}
+
+fn read_tile(reader: &mut ByteReader) -> std::io::Result<TileType> {
+    Ok(match reader.u8()? {
+        0 => TileType::Empty,
+        1 => TileType::SolidWall,
+        2 => TileType::BreakableWall(Box::new(TileType::Empty)),
+        3 => TileType::Bomb(None),
+        4 => TileType::Bomb(Some(read_bomb(reader)?)),
+        5 => {
+            let remaining = reader.u32()?;
+            TileType::Explosion(
+                (remaining != u32::MAX).then_some(remaining),
+                Box::new(TileType::Empty),
+            )
+        }
+        6 => TileType::PowerUp(PowerUpType::Firepower),
+        7 => TileType::PowerUp(PowerUpType::ExtraBomb),
+        8 => TileType::PowerUp(PowerUpType::Kick),
+        9 => TileType::PowerUp(PowerUpType::Glove),
+        10 => TileType::PowerUp(PowerUpType::Remote),
+        11 => TileType::PowerUp(PowerUpType::Speed),
+        12 => TileType::PowerUp(PowerUpType::Pierce),
+        13 => TileType::PowerUp(PowerUpType::LineBomb),
+        14 => TileType::PowerUp(PowerUpType::Skull),
+        15 => TileType::PowerUp(PowerUpType::Shield),
+        tag => return Err(invalid_data(&format!("unknown tile {}", tag))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_follows_server() {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let address = socket.local_addr().unwrap();
+        let mut server = GameServer::new(socket, Some(1234), Ruleset::default()).unwrap();
+        let mut client = ServerClient::new(address).unwrap();
+
+        let drop_bomb = PlayerControl {
+            motion: Vec2::ZERO,
+            action: PlayerAction::DropBomb,
+        };
+        client.send_control(drop_bomb).unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        server.tick();
+        std::thread::sleep(Duration::from_millis(10));
+
+        assert_eq!(server.num_clients(), 1);
+        let snapshot = client.receive().unwrap();
+        assert_eq!(snapshot.seed, 1234);
+        assert_eq!(snapshot.sim.tick(), server.sim().tick());
+        assert_eq!(snapshot.sim.rules(), server.sim().rules());
+        assert_eq!(snapshot.sim.seed(), server.sim().seed());
+        assert_eq!(snapshot.sim.next_wall_drop(), server.sim().next_wall_drop());
+
+        let spawn_point = server.sim().tiles().spawn_points()[0];
+        assert_eq!(snapshot.sim.players()[0].position, spawn_point.as_vec2());
+        assert!(matches!(
+            snapshot.sim.tiles()[spawn_point],
+            TileType::Bomb(Some(_))
+        ));
+
+        // The client sees the walls but not what's inside them
+        for pos in server.sim().tiles().pos_iter() {
+            match &snapshot.sim.tiles()[pos] {
+                TileType::BreakableWall(contents) => assert_eq!(**contents, TileType::Empty),
+                tile => assert_eq!(*tile, server.sim().tiles()[pos]),
+            }
+        }
+    }
+
+    #[test]
+    fn test_many_pickups_round_trip() {
+        let tiles = make_basic_map(MAP_DIMENSIONS.0, MAP_DIMENSIONS.1, &mut MapSeed(5).rng());
+        let mut player = Player::new(0, PlayerController::Online(0));
+        player.position = tiles.spawn_points()[0].as_vec2();
+        player.shields = 300;
+        player.power_ups = vec![PowerUpType::Shield; 300];
+        let sim = GameSim::from_state(tiles, vec![player], Vec::new(), 0, Ruleset::default(), 5);
+
+        let bytes = snapshot_to_bytes(1, 5, &sim, &[]).unwrap();
+        let snapshot = snapshot_from_bytes(&bytes).unwrap();
+        assert_eq!(snapshot.sim.players()[0].shields, 300);
+        assert_eq!(
+            snapshot.sim.players()[0].power_ups,
+            sim.players()[0].power_ups
+        );
+    }
+
+    #[test]
+    fn test_largest_map_fits_a_snapshot() {
+        let rules_for = |side| Ruleset {
+            map_width: side,
+            map_height: side,
+            ..Ruleset::default()
+        };
+        let map_for = |side| make_basic_map_with_rules(&rules_for(side), &mut MapSeed(1).rng());
+        let fits = |side| max_snapshot_len(&map_for(side), &rules_for(side)) <= MAX_SNAPSHOT_LEN;
+        let side = (MAP_DIMENSIONS.0..)
+            .take_while(|side| fits(*side))
+            .last()
+            .unwrap();
+        assert!(!fits(side + 1));
+
+        // Fill the largest map with sliding bombs that all go off at once, and
+        // hand out a power-up for every tile
+        let tiles = map_for(side);
+        let num_tiles = side * side;
+        let mut players: Vec<_> = (0..tiles.spawn_points().len())
+            .map(|player_index| Player::new(player_index, PlayerController::Online(player_index)))
+            .collect();
+        players[0].power_ups = vec![PowerUpType::Firepower; num_tiles];
+        let mut bomb = Bomb::new(BOMB_EXPLOSION_DELAY, 1, 0);
+        bomb.remote = Some(0);
+        bomb.slide = Some(Slide {
+            direction: IVec2::X,
+            progress: 0,
+        });
+        let mut sim = GameSim::from_state(tiles, players, Vec::new(), 0, rules_for(side), 1);
+        let tiles = sim.tiles_mut();
+        let events: Vec<_> = tiles.pos_iter().map(SimEvent::BombExploded).collect();
+        for pos in tiles.pos_iter() {
+            tiles[pos] = TileType::Bomb(Some(bomb));
+        }
+
+        let bytes = snapshot_to_bytes(0, 1, &sim, &events).unwrap();
+        assert!(bytes.len() > MAX_SNAPSHOT_LEN * 3 / 4);
+        assert!(bytes.len() <= max_snapshot_len(sim.tiles(), sim.rules()));
+        let snapshot = snapshot_from_bytes(&bytes).unwrap();
+        assert_eq!(snapshot.sim.tiles().width(), side);
+        assert_eq!(snapshot.events.len(), num_tiles);
+    }
+}
@@ -0,0 +1,1473 @@
+use crate::map::TileGrid;
+use crate::nav::flood_fill;
+use crate::player::*;
+use crate::rules::Ruleset;
+use crate::sdf::{closest_dist_to_tile, map_sdf};
+use crate::tile::*;
+use bevy::prelude::*;
+use rand::seq::IteratorRandom;
+use rand::SeedableRng;
+use rand_chacha::ChaCha8Rng;
+use strum::IntoEnumIterator;
+
+// The simulation advances in fixed ticks so that it plays the same regardless
+// of frame rate. All durations below are counted in ticks.
+pub const TICKS_PER_SECOND: u32 = 60;
+pub const TICK_SECONDS: f32 = 1.0 / TICKS_PER_SECOND as f32;
+
+// Player speed in tiles per second at the start of a classic match, and what
+// each speed power-up adds
+pub const PLAYER_SPEED: f32 = 3.0 * TILE_SIZE;
+pub const SPEED_INCREMENT: f32 = 0.5 * TILE_SIZE;
+// Players move in substeps no longer than a step at the starting speed, so
+// that however fast they go they can't skip past the border kept around walls
+pub const MAX_SUBSTEP_MOTION: f32 = PLAYER_SPEED * TICK_SECONDS;
+// Defaults for the classic rules, see `Ruleset`
+pub const EXPLOSION_DURATION: u32 = TICKS_PER_SECOND / 10;
+pub const BOMB_EXPLOSION_DELAY: u32 = 3 * TICKS_PER_SECOND;
+pub const FREE_SPACE_BORDER: f32 = 0.4;
+// Ticks a kicked bomb takes to slide one tile
+pub const BOMB_SLIDE_TICKS: u32 = TICKS_PER_SECOND / 10;
+
+// How far a glove throws a bomb, and how long it takes to fly each tile
+pub const THROW_DISTANCE: i32 = 3;
+pub const BOMB_THROW_TICKS_PER_TILE: u32 = TICKS_PER_SECOND / 12;
+
+// How long a skull's curse lasts, and what the slow and short fuse curses do
+pub const CURSE_DURATION: u32 = 10 * TICKS_PER_SECOND;
+pub const CURSED_SPEED: f32 = 1.5 * TILE_SIZE;
+pub const SHORT_FUSE: u32 = TICKS_PER_SECOND;
+// Players closer than this touch, which passes curses on
+pub const PLAYER_CONTACT_DISTANCE: f32 = 0.6 * TILE_SIZE;
+// How long explosions can't hurt a player after a shield soaks up a hit
+pub const INVULNERABLE_TICKS: u32 = 2 * TICKS_PER_SECOND;
+
+// How far a bomb has slid from its tile, for drawing it between tiles
+pub fn bomb_offset(bomb: &Bomb) -> Vec2 {
+    match bomb.slide {
+        Some(slide) => slide.direction.as_vec2() * slide.progress as f32 / BOMB_SLIDE_TICKS as f32,
+        None => Vec2::ZERO,
+    }
+}
+
+// Moves a player by `distance` in the direction of `motion`, sliding along
+// walls rather than into them once within `border` of them
+fn move_player(
+    tiles: &TileGrid,
+    pos_in_map: Vec2,
+    motion: Vec2,
+    distance: f32,
+    border: f32,
+) -> Vec2 {
+    let new_pos_in_map = pos_in_map + distance * motion;
+    let (Some(icur_pos_in_map), Some(_)) = (
+        tiles.get_index_from_position(pos_in_map),
+        tiles.get_index_from_position(new_pos_in_map),
+    ) else {
+        return new_pos_in_map;
+    };
+
+    // A player standing on a bomb, e.g. one they just dropped, can walk off it
+    let cur_tile_is_bomb = matches!(tiles[icur_pos_in_map], TileType::Bomb(_));
+    let new_sdf = map_sdf(
+        tiles,
+        new_pos_in_map,
+        if cur_tile_is_bomb {
+            Some(icur_pos_in_map)
+        } else {
+            None
+        },
+    );
+
+    if -border < new_sdf.0 && new_sdf.0 < 0.0 {
+        pos_in_map + new_sdf.1 * distance
+    } else {
+        new_pos_in_map
+    }
+}
+
+// The grid direction closest to a motion, if there is any motion
+fn motion_direction(motion: Vec2) -> Option<IVec2> {
+    if motion == Vec2::ZERO {
+        None
+    } else if motion.x.abs() > motion.y.abs() {
+        Some(IVec2::new(motion.x.signum() as i32, 0))
+    } else {
+        Some(IVec2::new(0, motion.y.signum() as i32))
+    }
+}
+
+// Random choices in the simulation are drawn from the match's seed and the
+// tick, so that it needs no random state of its own and every copy of a game
+// makes the same choices. Each kind of choice made for each player gets a
+// stream of its own.
+const CURSE_STREAM: u64 = 0;
+const SCATTER_STREAM: u64 = 1;
+pub(crate) const BOT_STREAM: u64 = 2;
+
+pub(crate) fn tick_rng(seed: u64, tick: u64, kind: u64, player_index: usize) -> ChaCha8Rng {
+    let mut key = [0; 32];
+    key[..8].copy_from_slice(&seed.to_le_bytes());
+    key[8..16].copy_from_slice(&tick.to_le_bytes());
+    let mut rng = ChaCha8Rng::from_seed(key);
+    rng.set_stream(kind << 32 | player_index as u64);
+    rng
+}
+
+// Every tile of a map in a clockwise spiral, from the top left corner of the
+// outer ring in towards the centre
+pub fn spiral_order(width: usize, height: usize) -> Vec<IVec2> {
+    let mut order = Vec::with_capacity(width * height);
+    let mut min = IVec2::ZERO;
+    let mut max = IVec2::new(width as i32 - 1, height as i32 - 1);
+    while min.x <= max.x && min.y <= max.y {
+        order.extend((min.x..=max.x).map(|x| IVec2::new(x, min.y)));
+        order.extend((min.y + 1..=max.y).map(|y| IVec2::new(max.x, y)));
+        if min.y < max.y {
+            order.extend((min.x..max.x).rev().map(|x| IVec2::new(x, max.y)));
+        }
+        if min.x < max.x {
+            order.extend((min.y + 1..max.y).rev().map(|y| IVec2::new(min.x, y)));
+        }
+        min += IVec2::ONE;
+        max -= IVec2::ONE;
+    }
+    order
+}
+
+// Things that happened during a step which a view may want to react to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SimEvent {
+    BombExploded(IVec2),
+    PlayerDied(usize),
+    PowerUpCollected(usize, PowerUpType),
+    // A shield saved the player from an explosion
+    ShieldBroken(usize),
+    // A closing wall landed in sudden death
+    WallDropped(IVec2),
+}
+
+// What an explosion does to a player caught in it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Hit {
+    Ignored,
+    Absorbed,
+    Fatal,
+}
+
+// The damage step, where anything that protects a player gets to step in
+// before an explosion kills them
+fn take_hit(player: &mut Player) -> Hit {
+    if player.invulnerable > 0 {
+        return Hit::Ignored;
+    }
+    if player.shields > 0 {
+        player.shields -= 1;
+        player.invulnerable = INVULNERABLE_TICKS;
+        // A used up shield isn't dropped again when the player dies
+        if let Some(index) = player
+            .power_ups
+            .iter()
+            .position(|power_up| *power_up == PowerUpType::Shield)
+        {
+            player.power_ups.remove(index);
+        }
+        return Hit::Absorbed;
+    }
+    Hit::Fatal
+}
+
+// A bomb thrown with the glove. It flies over everything in `direction`, and
+// if the tile it comes down on isn't free it bounces on one tile at a time,
+// wrapping around the edges of the map. Its fuse keeps burning in the air.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ThrownBomb {
+    pub bomb: Bomb,
+    pub from: IVec2,
+    pub direction: IVec2,
+    // Tiles from `from` to where it comes down
+    pub distance: i32,
+    // Ticks since it left `from`
+    pub progress: u32,
+    // Tiles flown since it was thrown
+    pub travelled: i32,
+}
+
+impl ThrownBomb {
+    fn flight_ticks(&self) -> u32 {
+        self.distance as u32 * BOMB_THROW_TICKS_PER_TILE
+    }
+
+    fn flight_fraction(&self) -> f32 {
+        self.progress as f32 / self.flight_ticks() as f32
+    }
+
+    // Where it is over the map, which may be past the edge it wraps around
+    pub fn position(&self) -> Vec2 {
+        self.from.as_vec2()
+            + self.direction.as_vec2() * self.distance as f32 * self.flight_fraction()
+    }
+
+    // Height above the ground in tiles, arcing higher for longer throws
+    pub fn height(&self) -> f32 {
+        (std::f32::consts::PI * self.flight_fraction()).sin() * self.distance as f32 / 2.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Win(usize),
+    Draw,
+}
+
+// The complete game state and rules, independent of any renderer. The game
+// advances only when `step` is called, so it can be driven by the Bevy app,
+// a test or a bot without a window.
+#[derive(Resource, Clone, Default)]
+pub struct GameSim {
+    tiles: TileGrid,
+    players: Vec<Player>,
+    thrown_bombs: Vec<ThrownBomb>,
+    tick: u64,
+    rules: Ruleset,
+    // Seed of the match, usually the map's
+    seed: u64,
+    // Where closing walls land in sudden death, in turn, worked out once per
+    // map
+    spiral: Vec<IVec2>,
+}
+
+impl GameSim {
+    pub fn new(tiles: TileGrid) -> Self {
+        Self::with_rules(tiles, Ruleset::default())
+    }
+
+    pub fn with_rules(tiles: TileGrid, rules: Ruleset) -> Self {
+        let spiral = spiral_order(tiles.width(), tiles.height());
+        let mut sim = Self {
+            tiles,
+            players: Vec::new(),
+            thrown_bombs: Vec::new(),
+            tick: 0,
+            rules,
+            seed: 0,
+            spiral,
+        };
+        sim.remove_disabled_power_ups();
+        sim
+    }
+
+    // A game in the given state, e.g. as received from a server
+    pub fn from_state(
+        tiles: TileGrid,
+        players: Vec<Player>,
+        thrown_bombs: Vec<ThrownBomb>,
+        tick: u64,
+        rules: Ruleset,
+        seed: u64,
+    ) -> Self {
+        let spiral = spiral_order(tiles.width(), tiles.height());
+        Self {
+            tiles,
+            players,
+            thrown_bombs,
+            tick,
+            rules,
+            seed,
+            spiral,
+        }
+    }
+
+    // Number of ticks since the start of the round
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn tiles(&self) -> &TileGrid {
+        &self.tiles
+    }
+
+    pub fn tiles_mut(&mut self) -> &mut TileGrid {
+        &mut self.tiles
+    }
+
+    pub fn players(&self) -> &[Player] {
+        &self.players
+    }
+
+    pub fn thrown_bombs(&self) -> &[ThrownBomb] {
+        &self.thrown_bombs
+    }
+
+    pub fn rules(&self) -> &Ruleset {
+        &self.rules
+    }
+
+    // Players start with the new rules' stats from the next `restart`
+    pub fn set_rules(&mut self, rules: Ruleset) {
+        self.rules = rules;
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Random choices such as curses depend on the seed, so that they differ
+    // from match to match
+    pub fn set_seed(&mut self, seed: u64) {
+        self.seed = seed;
+    }
+
+    pub fn player_for_controller(&self, controller: PlayerController) -> Option<usize> {
+        self.players
+            .iter()
+            .position(|player| player.controller == controller)
+    }
+
+    // Adds a player at the next free spawn point, returning its index
+    pub fn add_player(&mut self, controller: PlayerController) -> Option<usize> {
+        let player_index = self.players.len();
+        let Some(spawn_point) = self.tiles.spawn_points().get(player_index) else {
+            eprintln!("No spawn point for player {}", player_index);
+            return None;
+        };
+
+        let mut player = Player::new(player_index, controller);
+        player.reset(&self.rules);
+        player.position = spawn_point.as_vec2();
+        self.players.push(player);
+        Some(player_index)
+    }
+
+    // Starts a new round on the given map, keeping the existing players
+    pub fn restart(&mut self, tiles: TileGrid) {
+        self.spiral = spiral_order(tiles.width(), tiles.height());
+        self.tiles = tiles;
+        self.remove_disabled_power_ups();
+        self.thrown_bombs.clear();
+        self.tick = 0;
+        for player in &mut self.players {
+            player.reset(&self.rules);
+            if let Some(spawn_point) = self.tiles.spawn_points().get(player.player_index) {
+                player.position = spawn_point.as_vec2();
+            }
+        }
+    }
+
+    // Power-ups that the rules don't enable never appear, even on a map file
+    // that has them. Walls that hid one are left hiding nothing.
+    fn remove_disabled_power_ups(&mut self) {
+        let rules = &self.rules;
+        let is_disabled = |tile: &TileType| matches!(tile, TileType::PowerUp(power_up) if !rules.is_enabled(*power_up));
+        for pos in self.tiles.pos_iter() {
+            match &mut self.tiles[pos] {
+                tile if is_disabled(tile) => *tile = TileType::Empty,
+                TileType::BreakableWall(contents) if is_disabled(contents) => {
+                    **contents = TileType::Empty
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Advance the game by one tick. `inputs[i]` is the control for player `i`;
+    // players without an entry stand still.
+    pub fn step(&mut self, inputs: &[PlayerControl]) -> Vec<SimEvent> {
+        let mut events = Vec::new();
+
+        for player_index in 0..self.players.len() {
+            let control = inputs.get(player_index).copied().unwrap_or_default();
+            if self.players[player_index].alive {
+                let control = self.players[player_index].cursed_control(control);
+                self.control_player(player_index, &control);
+            }
+        }
+
+        self.map_transitions(&mut events);
+        self.close_walls(&mut events);
+        self.check_for_crushed(&mut events);
+        self.check_for_death(&mut events);
+        self.check_pickup(&mut events);
+        self.update_curses();
+        self.tick += 1;
+
+        events
+    }
+
+    pub fn outcome(&self) -> Option<Outcome> {
+        if self.players.len() < 2 {
+            return None;
+        }
+
+        let mut alive = self.players.iter().filter(|player| player.alive);
+        match (alive.next(), alive.next()) {
+            (Some(winner), None) => Some(Outcome::Win(winner.player_index)),
+            (None, _) => Some(Outcome::Draw),
+            _ => None,
+        }
+    }
+
+    // The tile that the next closing wall lands on in sudden death, and how
+    // many ticks until it does. Walls land in turn on each tile of the spiral
+    // that isn't already solid, so no state is needed to know where.
+    pub fn next_wall_drop(&self) -> Option<(IVec2, u64)> {
+        let start = self.rules.sudden_death? as u64;
+        let interval = self.rules.sudden_death_interval.max(1) as u64;
+        let ticks_left = match self.tick.checked_sub(start) {
+            None => start - self.tick,
+            Some(since) => (interval - since % interval) % interval,
+        };
+        let pos = *self
+            .spiral
+            .iter()
+            .find(|pos| self.tiles[**pos] != TileType::SolidWall)?;
+        Some((pos, ticks_left))
+    }
+
+    // The remote bomb that the player's next detonation sets off, which is the
+    // first one they placed
+    pub fn next_remote_bomb(&self, player_index: usize) -> Option<IVec2> {
+        self.tiles
+            .pos_iter()
+            .filter_map(|pos| match &self.tiles[pos] {
+                TileType::Bomb(Some(bomb)) if bomb.player_index == player_index => {
+                    bomb.remote.map(|placed| (placed, pos))
+                }
+                _ => None,
+            })
+            .min_by_key(|(placed, _)| *placed)
+            .map(|(_, pos)| pos)
+    }
+
+    fn control_player(&mut self, player_index: usize, control: &PlayerControl) {
+        if control.action == PlayerAction::Detonate {
+            if let Some(pos) = self.next_remote_bomb(player_index) {
+                if let TileType::Bomb(Some(bomb)) = &mut self.tiles[pos] {
+                    bomb.fuse = 0;
+                }
+            }
+        }
+
+        let player = &mut self.players[player_index];
+
+        let cur_pos_in_map = player.position;
+        let step_motion = player.current_speed() * TICK_SECONDS;
+        let wanted_pos_in_map = cur_pos_in_map + step_motion * control.motion;
+        let num_substeps = (step_motion / MAX_SUBSTEP_MOTION).ceil().max(1.0);
+        let mut new_pos_in_map = cur_pos_in_map;
+        for _ in 0..num_substeps as u32 {
+            new_pos_in_map = move_player(
+                &self.tiles,
+                new_pos_in_map,
+                control.motion,
+                step_motion / num_substeps,
+                self.rules.free_space_border,
+            );
+        }
+
+        let icur_pos_in_map = self.tiles.get_index_from_position(cur_pos_in_map);
+        let inew_pos_in_map = self.tiles.get_index_from_position(new_pos_in_map);
+
+        if let (Some(icur_pos_in_map), Some(_)) = (icur_pos_in_map, inew_pos_in_map) {
+            // The glove lifts a bomb from under the player or in front of them
+            // and throws it the way they face
+            if control.action == PlayerAction::Throw && player.can_throw {
+                let dir = motion_direction(player.facing).unwrap_or(IVec2::Y);
+                let lifted = [icur_pos_in_map, icur_pos_in_map + dir]
+                    .into_iter()
+                    .find(|pos| {
+                        self.tiles.contains(*pos)
+                            && matches!(self.tiles[*pos], TileType::Bomb(Some(_)))
+                    });
+                if let Some(pos) = lifted {
+                    if let TileType::Bomb(Some(mut bomb)) = std::mem::take(&mut self.tiles[pos]) {
+                        bomb.slide = None;
+                        self.thrown_bombs.push(ThrownBomb {
+                            bomb,
+                            from: icur_pos_in_map,
+                            direction: dir,
+                            distance: THROW_DISTANCE,
+                            progress: 0,
+                            travelled: 0,
+                        });
+                    }
+                }
+            }
+
+            // Walking into a bomb with the kick power-up sends it sliding away
+            if let Some(dir) = motion_direction(control.motion).filter(|_| player.can_kick) {
+                let ahead = icur_pos_in_map + dir;
+                if self.tiles.contains(ahead)
+                    && closest_dist_to_tile(wanted_pos_in_map, ahead) < self.rules.free_space_border
+                {
+                    if let TileType::Bomb(Some(bomb)) = &mut self.tiles[ahead] {
+                        if bomb.slide.is_none() {
+                            bomb.slide = Some(Slide {
+                                direction: dir,
+                                progress: 0,
+                            });
+                        }
+                    }
+                }
+            }
+        }
+
+        player.position = new_pos_in_map;
+        player.is_moving = control.motion.length() > 0.0;
+        if player.is_moving {
+            player.facing = control.motion.normalize();
+        }
+
+        if let (PlayerAction::DropBomb, Some(pos)) = (control.action, inew_pos_in_map) {
+            self.drop_bombs(player_index, pos);
+        }
+    }
+
+    // Drops a bomb on the player's tile. With the line bomb, dropping again
+    // while standing on a bomb lays out the rest of the player's bombs in the
+    // direction they face, up to the first tile that isn't free.
+    fn drop_bombs(&mut self, player_index: usize, pos: IVec2) {
+        let player = &self.players[player_index];
+        let line: Vec<IVec2> = match self.tiles[pos] {
+            TileType::Empty => vec![pos],
+            TileType::Bomb(Some(_)) if player.has_line_bomb => {
+                let dir = motion_direction(player.facing).unwrap_or(IVec2::Y);
+                (1..=player.num_bombs)
+                    .map(|dist| pos + dir * dist)
+                    .take_while(|pos| self.tiles.contains(*pos) && self.is_free(*pos))
+                    .collect()
+            }
+            _ => Vec::new(),
+        };
+
+        let mut bomb = Bomb::new(player.bomb_fuse(&self.rules), player.firepower, player_index);
+        bomb.pierce = player.has_pierce;
+        let has_remote = player.has_remote;
+        for pos in line.into_iter().take(player.num_bombs.max(0) as usize) {
+            let player = &mut self.players[player_index];
+            bomb.remote = has_remote.then_some(player.bombs_placed);
+            player.bombs_placed += 1;
+            player.num_bombs -= 1;
+            self.tiles[pos] = TileType::Bomb(Some(bomb));
+        }
+    }
+
+    fn map_transitions(&mut self, events: &mut Vec<SimEvent>) {
+        // Count down every timer first so that anything created below gets
+        // its full duration
+        for pos in self.tiles.pos_iter() {
+            match &mut self.tiles[pos] {
+                TileType::Bomb(Some(bomb)) if bomb.remote.is_none() => {
+                    bomb.fuse = bomb.fuse.saturating_sub(1);
+                }
+                TileType::Explosion(Some(remaining), _) => {
+                    *remaining = remaining.saturating_sub(1);
+                }
+                _ => {}
+            }
+        }
+
+        // Kicked bombs keep their fuse while they slide. Which ones are
+        // sliding is decided first, so that none moves twice.
+        let sliding: Vec<_> = self
+            .tiles
+            .pos_iter()
+            .filter(|pos| {
+                matches!(&self.tiles[*pos], TileType::Bomb(Some(bomb)) if bomb.slide.is_some())
+            })
+            .collect();
+        for pos in sliding {
+            self.slide_bomb(pos);
+        }
+        self.fly_thrown_bombs();
+
+        for pos in self.tiles.pos_iter() {
+            match self.tiles[pos].clone() {
+                TileType::Bomb(Some(bomb)) if bomb.fuse == 0 => {
+                    self.explode_bomb(pos, &bomb);
+                    events.push(SimEvent::BombExploded(pos));
+                }
+                TileType::Explosion(Some(0), contents) => {
+                    self.tiles[pos] = *contents;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    // Moves a sliding bomb on towards the next tile, or stops it if a wall,
+    // player, power-up or another bomb is in the way
+    fn slide_bomb(&mut self, pos: IVec2) {
+        let TileType::Bomb(Some(mut bomb)) = self.tiles[pos].clone() else {
+            return;
+        };
+        let Some(mut slide) = bomb.slide else {
+            return;
+        };
+
+        let next = pos + slide.direction;
+        if !self.tiles.contains(next) || !self.is_free(next) {
+            bomb.slide = None;
+            self.tiles[pos] = TileType::Bomb(Some(bomb));
+            return;
+        }
+
+        slide.progress += 1;
+        if slide.progress < BOMB_SLIDE_TICKS {
+            bomb.slide = Some(slide);
+            self.tiles[pos] = TileType::Bomb(Some(bomb));
+        } else {
+            slide.progress = 0;
+            bomb.slide = Some(slide);
+            self.tiles[pos] = TileType::Empty;
+            self.tiles[next] = TileType::Bomb(Some(bomb));
+        }
+    }
+
+    // Whether a bomb can move onto a tile
+    fn is_free(&self, pos: IVec2) -> bool {
+        self.tiles[pos] == TileType::Empty
+            && !self.players.iter().any(|player| {
+                player.alive && self.tiles.get_index_from_position(player.position) == Some(pos)
+            })
+    }
+
+    fn fly_thrown_bombs(&mut self) {
+        let size = IVec2::new(self.tiles.width() as i32, self.tiles.height() as i32);
+        for mut thrown in std::mem::take(&mut self.thrown_bombs) {
+            if thrown.bomb.remote.is_none() {
+                thrown.bomb.fuse = thrown.bomb.fuse.saturating_sub(1);
+            }
+            thrown.progress += 1;
+            if thrown.progress < thrown.flight_ticks() {
+                self.thrown_bombs.push(thrown);
+                continue;
+            }
+
+            let landing = (thrown.from + thrown.direction * thrown.distance).rem_euclid(size);
+            thrown.travelled += thrown.distance;
+            if self.is_free(landing) {
+                self.tiles[landing] = TileType::Bomb(Some(thrown.bomb));
+            } else if thrown.travelled > size.max_element() + THROW_DISTANCE {
+                // Nowhere to land all the way round, so the bomb is lost and
+                // its owner gets it back
+                if let Some(player) = self.players.get_mut(thrown.bomb.player_index) {
+                    player.num_bombs += 1;
+                }
+            } else {
+                thrown.from = landing;
+                thrown.distance = 1;
+                thrown.progress = 0;
+                self.thrown_bombs.push(thrown);
+            }
+        }
+    }
+
+    fn explode_bomb(&mut self, pos: IVec2, bomb: &Bomb) {
+        let duration = self.rules.explosion_duration;
+        self.tiles[pos] = TileType::Explosion(Some(duration), Box::new(TileType::Empty));
+
+        // increase a bomb counter for the player that placed the bomb
+        if let Some(player) = self.players.get_mut(bomb.player_index) {
+            player.num_bombs += 1;
+        }
+
+        for dir in [IVec2::new(0, 1), IVec2::new(0, -1), IVec2::new(1, 0), IVec2::new(-1, 0)] {
+            for dist in 1..=bomb.firepower {
+                let pos = pos + dir * dist;
+                if !self.tiles.contains(pos) {
+                    continue;
+                }
+
+                let tile = &mut self.tiles[pos];
+                match tile {
+                    TileType::Empty | TileType::PowerUp(_) => {
+                        *tile = TileType::Explosion(Some(duration), Box::new(TileType::Empty));
+                    }
+                    TileType::BreakableWall(contents) => {
+                        *tile = TileType::Explosion(Some(duration), contents.clone());
+                        if !bomb.pierce {
+                            break; // don't go through walls
+                        }
+                    }
+                    TileType::SolidWall => {
+                        break; // don't go through walls
+                    }
+                    TileType::Bomb(Some(other_bomb)) => {
+                        other_bomb.fuse = 0;
+                    }
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    // A closing wall crushes any bomb, power-up or wall where it lands
+    fn close_walls(&mut self, events: &mut Vec<SimEvent>) {
+        if let Some((pos, 0)) = self.next_wall_drop() {
+            // A crushed bomb goes back to its owner, as if it had exploded
+            if let TileType::Bomb(Some(bomb)) = &self.tiles[pos] {
+                if let Some(player) = self.players.get_mut(bomb.player_index) {
+                    player.num_bombs += 1;
+                }
+            }
+            self.tiles[pos] = TileType::SolidWall;
+            events.push(SimEvent::WallDropped(pos));
+        }
+    }
+
+    // Players under a closing wall die, whatever would protect them from a
+    // blast
+    fn check_for_crushed(&mut self, events: &mut Vec<SimEvent>) {
+        let mut crushed = Vec::new();
+        for player in self.players.iter_mut().filter(|player| player.alive) {
+            if let Some(pos) = self.tiles.get_index_from_position(player.position) {
+                if self.tiles[pos] == TileType::SolidWall {
+                    player.alive = false;
+                    player.is_moving = false;
+                    events.push(SimEvent::PlayerDied(player.player_index));
+                    crushed.push((player.player_index, pos));
+                }
+            }
+        }
+
+        for (player_index, pos) in crushed {
+            self.release_remote_bombs(player_index);
+            self.scatter_power_ups(player_index, pos);
+        }
+    }
+
+    fn check_for_death(&mut self, events: &mut Vec<SimEvent>) {
+        let mut died = Vec::new();
+        for player in self.players.iter_mut().filter(|player| player.alive) {
+            player.invulnerable = player.invulnerable.saturating_sub(1);
+            if let Some(pos) = self.tiles.get_index_from_position(player.position) {
+                if let TileType::Explosion(_, _) = self.tiles[pos] {
+                    match take_hit(player) {
+                        Hit::Ignored => {}
+                        Hit::Absorbed => events.push(SimEvent::ShieldBroken(player.player_index)),
+                        Hit::Fatal => {
+                            player.alive = false;
+                            player.is_moving = false;
+                            events.push(SimEvent::PlayerDied(player.player_index));
+                            died.push((player.player_index, pos));
+                        }
+                    }
+                }
+            }
+        }
+
+        for (player_index, pos) in died {
+            self.release_remote_bombs(player_index);
+            self.scatter_power_ups(player_index, pos);
+        }
+    }
+
+    // A dead player's power-ups land on random empty tiles that can be reached
+    // from where they died. Any that don't fit are lost.
+    fn scatter_power_ups(&mut self, player_index: usize, pos: IVec2) {
+        let mut power_ups = std::mem::take(&mut self.players[player_index].power_ups);
+        power_ups.retain(|power_up| self.rules.is_enabled(*power_up));
+        let reachable = flood_fill(&self.tiles, pos, |pos| {
+            !matches!(self.tiles[pos], TileType::SolidWall | TileType::BreakableWall(_))
+        });
+        let mut rng = tick_rng(self.seed, self.tick, SCATTER_STREAM, player_index);
+        let free = reachable
+            .into_iter()
+            .filter(|pos| self.is_free(*pos))
+            .choose_multiple(&mut rng, power_ups.len());
+        for (pos, power_up) in free.into_iter().zip(power_ups) {
+            self.tiles[pos] = TileType::PowerUp(power_up);
+        }
+    }
+
+    // Nobody is left to set off a dead player's remote bombs, so they start
+    // burning an ordinary fuse instead
+    fn release_remote_bombs(&mut self, player_index: usize) {
+        for pos in self.tiles.pos_iter() {
+            if let TileType::Bomb(Some(bomb)) = &mut self.tiles[pos] {
+                if bomb.player_index == player_index {
+                    bomb.remote = None;
+                }
+            }
+        }
+        for thrown in &mut self.thrown_bombs {
+            if thrown.bomb.player_index == player_index {
+                thrown.bomb.remote = None;
+            }
+        }
+    }
+
+    fn check_pickup(&mut self, events: &mut Vec<SimEvent>) {
+        let (seed, tick) = (self.seed, self.tick);
+        let rules = &self.rules;
+        for player in self.players.iter_mut().filter(|player| player.alive) {
+            if let Some(pos) = self.tiles.get_index_from_position(player.position) {
+                let tile = &mut self.tiles[pos];
+                if let TileType::PowerUp(power_up) = *tile {
+                    // Whether the power-up did anything, rather than being
+                    // wasted on a player already at a cap or holding one
+                    let took_effect = match power_up {
+                        PowerUpType::Firepower => {
+                            let took_effect = player.firepower < rules.max_firepower;
+                            if took_effect {
+                                player.firepower += 1;
+                            }
+                            took_effect
+                        }
+                        PowerUpType::ExtraBomb => {
+                            // Some of the player's bombs may be on the map, so
+                            // count how many they have in all
+                            let extra_bombs = player
+                                .power_ups
+                                .iter()
+                                .filter(|power_up| **power_up == PowerUpType::ExtraBomb)
+                                .count() as i32;
+                            let took_effect = rules.starting_bombs + extra_bombs < rules.max_bombs;
+                            if took_effect {
+                                player.num_bombs += 1;
+                            }
+                            took_effect
+                        }
+                        PowerUpType::Kick => !std::mem::replace(&mut player.can_kick, true),
+                        PowerUpType::Glove => !std::mem::replace(&mut player.can_throw, true),
+                        PowerUpType::Remote => !std::mem::replace(&mut player.has_remote, true),
+                        PowerUpType::Pierce => !std::mem::replace(&mut player.has_pierce, true),
+                        PowerUpType::LineBomb => {
+                            !std::mem::replace(&mut player.has_line_bomb, true)
+                        }
+                        PowerUpType::Shield => {
+                            player.shields += 1;
+                            true
+                        }
+                        PowerUpType::Skull => {
+                            let mut rng = tick_rng(seed, tick, CURSE_STREAM, player.player_index);
+                            player.curse = Curse::iter().choose(&mut rng).map(|curse| ActiveCurse {
+                                curse,
+                                remaining: CURSE_DURATION,
+                            });
+                            true
+                        }
+                        PowerUpType::Speed => {
+                            let took_effect = player.speed < rules.max_player_speed;
+                            player.speed =
+                                (player.speed + SPEED_INCREMENT).min(rules.max_player_speed);
+                            took_effect
+                        }
+                    };
+                    *tile = TileType::Empty;
+                    // Only power-ups that took effect are dropped again on death
+                    if took_effect {
+                        player.power_ups.push(power_up);
+                    }
+                    events.push(SimEvent::PowerUpCollected(player.player_index, power_up));
+                }
+            }
+        }
+    }
+
+    // Curses wear off, and pass from cursed players to those they touch
+    fn update_curses(&mut self) {
+        for player in &mut self.players {
+            if let Some(active) = &mut player.curse {
+                active.remaining = active.remaining.saturating_sub(1);
+                if active.remaining == 0 {
+                    player.curse = None;
+                }
+            }
+        }
+
+        let players = &self.players;
+        let caught: Vec<_> = players
+            .iter()
+            .filter(|player| player.alive && player.curse.is_none())
+            .filter_map(|player| {
+                let curse = players
+                    .iter()
+                    .filter(|other| {
+                        other.alive
+                            && other.position.distance(player.position) < PLAYER_CONTACT_DISTANCE
+                    })
+                    .find_map(|other| other.curse)?;
+                Some((player.player_index, curse))
+            })
+            .collect();
+        for (player_index, curse) in caught {
+            self.players[player_index].curse = Some(curse);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic::*;
+
+    fn drop_bomb() -> PlayerControl {
+        PlayerControl {
+            motion: Vec2::ZERO,
+            action: PlayerAction::DropBomb,
+        }
+    }
+
+    fn open_map(width: usize, height: usize, spawn_points: Vec<IVec2>) -> TileGrid {
+        let mut map = TileGrid::filled(width, height, TileType::Empty);
+        for pos in map.pos_iter() {
+            if map.is_edge(pos) {
+                map[pos] = TileType::SolidWall;
+            }
+        }
+        map.set_spawn_points(spawn_points);
+        map
+    }
+
+    fn run_for(sim: &mut GameSim, ticks: u32) -> Vec<SimEvent> {
+        let mut events = Vec::new();
+        for _ in 0..ticks {
+            events.extend(sim.step(&[]));
+        }
+        events
+    }
+
+    #[test]
+    fn test_bomb_explodes_after_delay() {
+        let mut sim = GameSim::new(open_map(7, 3, vec![IVec2::new(1, 1), IVec2::new(5, 1)]));
+        let placer = sim.add_player(PlayerController::KeyboardArrows).unwrap();
+        sim.add_player(PlayerController::KeyboardWASD).unwrap();
+
+        sim.step(&[drop_bomb()]);
+        assert!(matches!(sim.tiles()[IVec2::new(1, 1)], TileType::Bomb(Some(_))));
+        assert_eq!(sim.players()[placer].num_bombs, 0);
+
+        let events = run_for(&mut sim, BOMB_EXPLOSION_DELAY);
+        assert!(events.contains(&SimEvent::BombExploded(IVec2::new(1, 1))));
+        assert!(matches!(sim.tiles()[IVec2::new(2, 1)], TileType::Explosion(_, _)));
+        assert!(matches!(sim.tiles()[IVec2::new(3, 1)], TileType::Empty));
+        assert_eq!(sim.players()[placer].num_bombs, 1);
+
+        // The placer is standing on their own bomb, so the other player wins
+        assert!(events.contains(&SimEvent::PlayerDied(placer)));
+        assert_eq!(sim.outcome(), Some(Outcome::Win(1)));
+
+        run_for(&mut sim, EXPLOSION_DURATION);
+        assert_eq!(sim.tiles()[IVec2::new(1, 1)], TileType::Empty);
+    }
+
+    #[test]
+    fn test_explosion_reveals_wall_contents() {
+        let mut map = open_map(7, 3, vec![IVec2::new(1, 1)]);
+        map[IVec2::new(2, 1)] =
+            TileType::BreakableWall(Box::new(TileType::PowerUp(PowerUpType::Firepower)));
+        map[IVec2::new(3, 1)] = TileType::BreakableWall(Box::new(TileType::Empty));
+        let mut sim = GameSim::new(map);
+        sim.add_player(PlayerController::KeyboardArrows).unwrap();
+
+        sim.step(&[drop_bomb()]);
+        run_for(&mut sim, BOMB_EXPLOSION_DELAY + EXPLOSION_DURATION);
+
+        // The blast stops at the first wall
+        assert_eq!(
+            sim.tiles()[IVec2::new(2, 1)],
+            TileType::PowerUp(PowerUpType::Firepower)
+        );
+        assert!(matches!(sim.tiles()[IVec2::new(3, 1)], TileType::BreakableWall(_)));
+    }
+
+    #[test]
+    fn test_walls_block_movement() {
+        let mut sim = GameSim::new(make_really_basic_map1());
+        sim.add_player(PlayerController::KeyboardArrows).unwrap();
+        let right = PlayerControl {
+            motion: Vec2::X,
+            action: PlayerAction::None,
+        };
+
+        for _ in 0..100 {
+            sim.step(&[right]);
+        }
+
+        let position = sim.players()[0].position;
+        assert!(position.x < 1.0 + FREE_SPACE_BORDER);
+        assert_eq!(sim.tiles().get_index_from_position(position), Some(IVec2::new(1, 1)));
+    }
+
+    #[test]
+    fn test_pickup() {
+        let mut map = open_map(5, 3, vec![IVec2::new(1, 1)]);
+        map[IVec2::new(2, 1)] = TileType::PowerUp(PowerUpType::ExtraBomb);
+        let mut sim = GameSim::new(map);
+        sim.add_player(PlayerController::KeyboardArrows).unwrap();
+        let right = PlayerControl {
+            motion: Vec2::X,
+            action: PlayerAction::None,
+        };
+
+        let mut events = Vec::new();
+        for _ in 0..20 {
+            events.extend(sim.step(&[right]));
+        }
+
+        assert!(events.contains(&SimEvent::PowerUpCollected(0, PowerUpType::ExtraBomb)));
+        assert_eq!(sim.players()[0].num_bombs, 2);
+        assert_eq!(sim.tiles()[IVec2::new(2, 1)], TileType::Empty);
+    }
+
+    #[test]
+    fn test_kicked_bomb_slides_until_blocked() {
+        let mut map = open_map(9, 3, vec![IVec2::new(1, 1)]);
+        map[IVec2::new(2, 1)] = TileType::PowerUp(PowerUpType::Kick);
+        map[IVec2::new(3, 1)] = TileType::Bomb(Some(Bomb::new(100, 1, 0)));
+        map[IVec2::new(6, 1)] = TileType::PowerUp(PowerUpType::Firepower);
+        let mut sim = GameSim::new(map);
+        sim.add_player(PlayerController::KeyboardArrows).unwrap();
+        let right = PlayerControl {
+            motion: Vec2::X,
+            action: PlayerAction::None,
+        };
+
+        // Pick up the kick and walk on into the bomb
+        for _ in 0..30 {
+            sim.step(&[right]);
+        }
+        run_for(&mut sim, 30);
+
+        assert!(sim.players()[0].can_kick);
+        assert_eq!(sim.tiles()[IVec2::new(3, 1)], TileType::Empty);
+        assert_eq!(
+            sim.tiles()[IVec2::new(5, 1)],
+            TileType::Bomb(Some(Bomb::new(40, 1, 0)))
+        );
+        assert_eq!(
+            sim.tiles()[IVec2::new(6, 1)],
+            TileType::PowerUp(PowerUpType::Firepower)
+        );
+    }
+
+    #[test]
+    fn test_thrown_bomb_flies_over_walls() {
+        // # # # # # # # # #
+        // # 0 + + . + . + #
+        // # # # # # # # # #
+        let mut map = open_map(9, 3, vec![IVec2::new(1, 1)]);
+        for x in [2, 3, 5, 7] {
+            map[IVec2::new(x, 1)] = TileType::BreakableWall(Box::new(TileType::Empty));
+        }
+        map[IVec2::new(1, 1)] = TileType::PowerUp(PowerUpType::Glove);
+        let mut sim = GameSim::new(map);
+        sim.add_player(PlayerController::KeyboardArrows).unwrap();
+        let act = |motion, action| PlayerControl { motion, action };
+
+        // Pick up the glove, face the walls and throw a bomb over them
+        sim.step(&[act(Vec2::X, PlayerAction::None)]);
+        assert!(sim.players()[0].can_throw);
+        sim.step(&[drop_bomb()]);
+        sim.step(&[act(Vec2::ZERO, PlayerAction::Throw)]);
+        assert_eq!(sim.thrown_bombs().len(), 1);
+        assert_eq!(sim.tiles()[IVec2::new(1, 1)], TileType::Empty);
+
+        run_for(&mut sim, THROW_DISTANCE as u32 * BOMB_THROW_TICKS_PER_TILE);
+        assert!(sim.thrown_bombs().is_empty());
+        assert!(matches!(sim.tiles()[IVec2::new(4, 1)], TileType::Bomb(Some(_))));
+
+        // The next one comes down on the first and bounces on over a wall
+        let bomb_underfoot = Bomb::new(1000, 1, 0);
+        sim.tiles_mut()[IVec2::new(1, 1)] = TileType::Bomb(Some(bomb_underfoot));
+        sim.step(&[act(Vec2::ZERO, PlayerAction::Throw)]);
+        run_for(&mut sim, 5 * BOMB_THROW_TICKS_PER_TILE);
+        assert!(sim.thrown_bombs().is_empty());
+        assert!(matches!(sim.tiles()[IVec2::new(6, 1)], TileType::Bomb(Some(_))));
+
+        // With nowhere left to land, the last one wraps around the edges
+        // until its owner gets it back
+        let num_bombs = sim.players()[0].num_bombs;
+        sim.tiles_mut()[IVec2::new(1, 1)] = TileType::Bomb(Some(bomb_underfoot));
+        sim.step(&[act(Vec2::ZERO, PlayerAction::Throw)]);
+        run_for(&mut sim, 20 * BOMB_THROW_TICKS_PER_TILE);
+        assert!(sim.thrown_bombs().is_empty());
+        assert_eq!(sim.players()[0].num_bombs, num_bombs + 1);
+    }
+
+    #[test]
+    fn test_remote_bombs_wait_for_detonation() {
+        let mut map = open_map(7, 3, vec![IVec2::new(1, 1), IVec2::new(5, 1)]);
+        map[IVec2::new(1, 1)] = TileType::PowerUp(PowerUpType::Remote);
+        let mut sim = GameSim::new(map);
+        sim.add_player(PlayerController::KeyboardArrows).unwrap();
+        sim.add_player(PlayerController::KeyboardWASD).unwrap();
+        let act = |motion, action| PlayerControl { motion, action };
+
+        // Pick up the remote, drop a bomb and walk out of its way
+        sim.step(&[]);
+        assert!(sim.players()[0].has_remote);
+        sim.step(&[drop_bomb()]);
+        for _ in 0..40 {
+            sim.step(&[act(Vec2::X, PlayerAction::None)]);
+        }
+        let events = run_for(&mut sim, BOMB_EXPLOSION_DELAY);
+        assert!(events.is_empty());
+        assert_eq!(sim.next_remote_bomb(0), Some(IVec2::new(1, 1)));
+
+        let events = sim.step(&[act(Vec2::ZERO, PlayerAction::Detonate)]);
+        assert!(events.contains(&SimEvent::BombExploded(IVec2::new(1, 1))));
+        assert!(sim.players()[0].alive);
+
+        // When its owner dies, a remote bomb burns a full fuse
+        let mut bomb = Bomb::new(BOMB_EXPLOSION_DELAY, 1, 1);
+        bomb.remote = Some(0);
+        sim.tiles_mut()[IVec2::new(5, 1)] =
+            TileType::Explosion(Some(EXPLOSION_DURATION), Box::default());
+        sim.tiles_mut()[IVec2::new(4, 1)] = TileType::Bomb(Some(bomb));
+        let events = run_for(&mut sim, BOMB_EXPLOSION_DELAY);
+        assert!(events.contains(&SimEvent::PlayerDied(1)));
+        assert!(!events.contains(&SimEvent::BombExploded(IVec2::new(4, 1))));
+        let events = sim.step(&[]);
+        assert!(events.contains(&SimEvent::BombExploded(IVec2::new(4, 1))));
+    }
+
+    #[test]
+    fn test_pierce_burns_through_walls() {
+        let mut map = open_map(11, 3, vec![IVec2::new(1, 1), IVec2::new(9, 1)]);
+        map[IVec2::new(1, 1)] = TileType::PowerUp(PowerUpType::Pierce);
+        let mut sim = GameSim::new(map);
+        sim.add_player(PlayerController::KeyboardArrows).unwrap();
+        sim.add_player(PlayerController::KeyboardWASD).unwrap();
+
+        // Bombs dropped after the pickup pierce
+        sim.step(&[]);
+        assert!(sim.players()[0].has_pierce);
+        sim.step(&[drop_bomb()]);
+        assert!(matches!(sim.tiles()[IVec2::new(1, 1)], TileType::Bomb(Some(bomb)) if bomb.pierce));
+        sim.tiles_mut()[IVec2::new(1, 1)] = TileType::Empty;
+
+        // The blast goes through both walls and uncovers the power-up behind the first
+        let mut bomb = Bomb::new(1, 3, 1);
+        bomb.pierce = true;
+        sim.tiles_mut()[IVec2::new(5, 1)] = TileType::Bomb(Some(bomb));
+        sim.tiles_mut()[IVec2::new(6, 1)] =
+            TileType::BreakableWall(Box::new(TileType::PowerUp(PowerUpType::Firepower)));
+        sim.tiles_mut()[IVec2::new(7, 1)] = TileType::BreakableWall(Box::default());
+        sim.tiles_mut()[IVec2::new(4, 1)] = TileType::SolidWall;
+        run_for(&mut sim, 2);
+        for x in 6..=8 {
+            assert!(matches!(sim.tiles()[IVec2::new(x, 1)], TileType::Explosion(_, _)));
+        }
+        assert_eq!(sim.tiles()[IVec2::new(3, 1)], TileType::Empty);
+        assert!(sim.players()[1].alive);
+
+        run_for(&mut sim, EXPLOSION_DURATION);
+        assert_eq!(
+            sim.tiles()[IVec2::new(6, 1)],
+            TileType::PowerUp(PowerUpType::Firepower)
+        );
+    }
+
+    #[test]
+    fn test_line_bomb_fills_free_tiles_ahead() {
+        let mut map = open_map(9, 3, vec![IVec2::new(1, 1), IVec2::new(7, 1)]);
+        map[IVec2::new(4, 1)] = TileType::BreakableWall(Box::default());
+        map[IVec2::new(1, 1)] = TileType::PowerUp(PowerUpType::LineBomb);
+        let mut player = Player::new(0, PlayerController::KeyboardArrows);
+        player.position = Vec2::new(1.0, 1.0);
+        player.facing = Vec2::X;
+        player.num_bombs = 4;
+        let mut sim = GameSim::from_state(map, vec![player], Vec::new(), 0, Ruleset::default(), 0);
+
+        // The first drop is an ordinary bomb
+        sim.step(&[]);
+        assert!(sim.players()[0].has_line_bomb);
+        sim.step(&[drop_bomb()]);
+        assert_eq!(sim.players()[0].num_bombs, 3);
+
+        // With it the bombs go down the corridor up to the wall
+        sim.step(&[drop_bomb()]);
+        for x in 1..=3 {
+            assert!(matches!(sim.tiles()[IVec2::new(x, 1)], TileType::Bomb(Some(_))));
+        }
+        assert!(matches!(sim.tiles()[IVec2::new(4, 1)], TileType::BreakableWall(_)));
+        assert_eq!(sim.players()[0].num_bombs, 1);
+    }
+
+    #[test]
+    fn test_remote_bombs_go_off_in_laying_order() {
+        let map = open_map(9, 3, vec![IVec2::new(5, 1)]);
+        let mut player = Player::new(0, PlayerController::KeyboardArrows);
+        player.position = Vec2::new(5.0, 1.0);
+        player.facing = Vec2::NEG_X;
+        player.num_bombs = 3;
+        player.has_remote = true;
+        player.has_line_bomb = true;
+        let mut sim = GameSim::from_state(map, vec![player], Vec::new(), 0, Ruleset::default(), 0);
+
+        // A line laid leftwards on one tick, after the bomb underfoot
+        sim.step(&[drop_bomb()]);
+        sim.step(&[drop_bomb()]);
+        assert_eq!(sim.next_remote_bomb(0), Some(IVec2::new(5, 1)));
+        sim.tiles_mut()[IVec2::new(5, 1)] = TileType::Empty;
+        assert_eq!(sim.next_remote_bomb(0), Some(IVec2::new(4, 1)));
+        sim.tiles_mut()[IVec2::new(4, 1)] = TileType::Empty;
+        assert_eq!(sim.next_remote_bomb(0), Some(IVec2::new(3, 1)));
+    }
+
+    #[test]
+    fn test_curses_spread_and_wear_off() {
+        let mut map = open_map(7, 3, Vec::new());
+        map[IVec2::new(5, 1)] = TileType::PowerUp(PowerUpType::Skull);
+        let mut players: Vec<_> = [1.0, 1.3, 5.0]
+            .into_iter()
+            .enumerate()
+            .map(|(player_index, x)| {
+                let mut player = Player::new(player_index, PlayerController::Online(player_index));
+                player.position = Vec2::new(x, 1.0);
+                player
+            })
+            .collect();
+        players[0].curse = Some(ActiveCurse {
+            curse: Curse::Reversed,
+            remaining: 10,
+        });
+        let mut sim = GameSim::from_state(map, players, Vec::new(), 0, Ruleset::default(), 0);
+
+        // The skull curses whoever picks it up, and touching passes a curse on
+        sim.step(&[PlayerControl {
+            motion: Vec2::X,
+            action: PlayerAction::None,
+        }]);
+        assert!(sim.players()[0].position.x < 1.0);
+        assert!(sim.players()[1].has_curse(Curse::Reversed));
+        assert!(sim.players()[2].curse.is_some());
+
+        run_for(&mut sim, 10);
+        assert!(sim.players()[0].curse.is_none());
+        assert!(sim.players()[1].curse.is_none());
+        assert!(sim.players()[2].curse.is_some());
+    }
+
+    #[test]
+    fn test_curses_differ_between_matches() {
+        let curses: std::collections::HashSet<_> = (0..10)
+            .filter_map(|seed| {
+                let mut map = open_map(5, 3, vec![IVec2::new(1, 1)]);
+                map[IVec2::new(1, 1)] = TileType::PowerUp(PowerUpType::Skull);
+                let mut sim = GameSim::new(map);
+                sim.set_seed(seed);
+                sim.add_player(PlayerController::KeyboardArrows).unwrap();
+                sim.step(&[]);
+                sim.players()[0].curse.map(|active| active.curse)
+            })
+            .collect();
+        assert!(curses.len() > 1);
+    }
+
+    #[test]
+    fn test_power_ups_scatter_on_death() {
+        // The wall down the middle keeps the power-ups on the left
+        let mut map = open_map(9, 5, Vec::new());
+        for y in 1..4 {
+            map[IVec2::new(4, y)] = TileType::SolidWall;
+        }
+        map[IVec2::new(1, 1)] = TileType::Explosion(Some(EXPLOSION_DURATION), Box::default());
+        let mut players: Vec<_> = [IVec2::new(1, 1), IVec2::new(6, 2)]
+            .into_iter()
+            .enumerate()
+            .map(|(player_index, pos)| {
+                let mut player = Player::new(player_index, PlayerController::Online(player_index));
+                player.position = pos.as_vec2();
+                player
+            })
+            .collect();
+        players[0].power_ups =
+            vec![PowerUpType::Firepower, PowerUpType::ExtraBomb, PowerUpType::Kick];
+        let mut sim = GameSim::from_state(map, players, Vec::new(), 0, Ruleset::default(), 0);
+
+        let events = sim.step(&[]);
+        assert!(events.contains(&SimEvent::PlayerDied(0)));
+        let scattered: Vec<_> = sim
+            .tiles()
+            .pos_iter()
+            .filter(|pos| matches!(sim.tiles()[*pos], TileType::PowerUp(_)))
+            .collect();
+        assert_eq!(scattered.len(), 3);
+        assert!(scattered.iter().all(|pos| pos.x < 4));
+        assert!(sim.players()[0].power_ups.is_empty());
+    }
+
+    #[test]
+    fn test_wasted_pickups_are_not_kept() {
+        let mut map = open_map(7, 3, vec![IVec2::new(1, 1)]);
+        map[IVec2::new(1, 1)] = TileType::PowerUp(PowerUpType::Firepower);
+        let rules = Ruleset {
+            max_firepower: 1,
+            ..Ruleset::classic()
+        };
+        let mut sim = GameSim::with_rules(map, rules);
+        sim.add_player(PlayerController::KeyboardArrows).unwrap();
+        sim.step(&[]);
+        assert_eq!(sim.players()[0].firepower, 1);
+        assert!(sim.players()[0].power_ups.is_empty());
+
+        // A second kick does nothing for a player who can already kick
+        sim.tiles_mut()[IVec2::new(1, 1)] = TileType::PowerUp(PowerUpType::Kick);
+        sim.step(&[]);
+        sim.tiles_mut()[IVec2::new(1, 1)] = TileType::PowerUp(PowerUpType::Kick);
+        let events = sim.step(&[]);
+        assert!(events.contains(&SimEvent::PowerUpCollected(0, PowerUpType::Kick)));
+        assert_eq!(sim.players()[0].power_ups, [PowerUpType::Kick]);
+    }
+
+    #[test]
+    fn test_shield_absorbs_one_hit() {
+        let mut map = open_map(7, 3, vec![IVec2::new(1, 1), IVec2::new(5, 1)]);
+        map[IVec2::new(1, 1)] = TileType::PowerUp(PowerUpType::Shield);
+        let mut sim = GameSim::new(map);
+        sim.add_player(PlayerController::KeyboardArrows).unwrap();
+        sim.add_player(PlayerController::KeyboardWASD).unwrap();
+        sim.step(&[]);
+        assert_eq!(sim.players()[0].shields, 1);
+
+        // The shield breaks, and the player can't be hurt for a while after
+        let explosion = TileType::Explosion(Some(EXPLOSION_DURATION), Box::default());
+        sim.tiles_mut()[IVec2::new(1, 1)] = explosion.clone();
+        let events = sim.step(&[]);
+        assert!(events.contains(&SimEvent::ShieldBroken(0)));
+        assert!(sim.players()[0].alive);
+        assert_eq!(sim.players()[0].shields, 0);
+        assert!(sim.players()[0].power_ups.is_empty());
+        sim.tiles_mut()[IVec2::new(1, 1)] = explosion.clone();
+        assert!(run_for(&mut sim, EXPLOSION_DURATION).is_empty());
+
+        run_for(&mut sim, INVULNERABLE_TICKS);
+        sim.tiles_mut()[IVec2::new(1, 1)] = explosion;
+        assert!(sim.step(&[]).contains(&SimEvent::PlayerDied(0)));
+    }
+
+    #[test]
+    fn test_walls_close_in_for_sudden_death() {
+        assert_eq!(
+            spiral_order(3, 3),
+            [(0, 0), (1, 0), (2, 0), (2, 1), (2, 2), (1, 2), (0, 2), (0, 1), (1, 1)]
+                .map(|(x, y)| IVec2::new(x, y))
+        );
+
+        let mut map = open_map(5, 4, vec![IVec2::new(1, 1), IVec2::new(3, 2)]);
+        map[IVec2::new(2, 1)] = TileType::PowerUp(PowerUpType::Kick);
+        let rules = Ruleset {
+            sudden_death: Some(10),
+            sudden_death_interval: 2,
+            ..Ruleset::classic()
+        };
+        let mut sim = GameSim::with_rules(map, rules);
+        sim.add_player(PlayerController::KeyboardArrows).unwrap();
+        sim.add_player(PlayerController::KeyboardWASD).unwrap();
+        assert_eq!(sim.next_wall_drop(), Some((IVec2::new(1, 1), 10)));
+
+        // The first wall lands on the first open tile of the spiral, and the
+        // player there
+        let events = run_for(&mut sim, 11);
+        assert!(events.contains(&SimEvent::WallDropped(IVec2::new(1, 1))));
+        assert!(events.contains(&SimEvent::PlayerDied(0)));
+        assert_eq!(sim.tiles()[IVec2::new(1, 1)], TileType::SolidWall);
+        assert_eq!(sim.outcome(), Some(Outcome::Win(1)));
+
+        // The next crushes the power-up beside it
+        assert_eq!(sim.next_wall_drop(), Some((IVec2::new(2, 1), 1)));
+        run_for(&mut sim, 2);
+        assert_eq!(sim.tiles()[IVec2::new(2, 1)], TileType::SolidWall);
+
+        // In the end there is no room left for anyone
+        run_for(&mut sim, 8);
+        assert!(sim.tiles().pos_iter().all(|pos| sim.tiles()[pos] == TileType::SolidWall));
+        assert_eq!(sim.outcome(), Some(Outcome::Draw));
+        assert_eq!(sim.next_wall_drop(), None);
+
+        // The next round follows the spiral of its own map
+        let mut map = open_map(7, 4, vec![IVec2::new(1, 2), IVec2::new(3, 2)]);
+        for x in 1..6 {
+            map[IVec2::new(x, 1)] = TileType::SolidWall;
+        }
+        sim.restart(map);
+        assert_eq!(sim.next_wall_drop(), Some((IVec2::new(5, 2), 10)));
+    }
+
+    #[test]
+    fn test_disabled_power_ups_never_appear() {
+        let mut map = open_map(7, 3, vec![IVec2::new(1, 1)]);
+        map[IVec2::new(2, 1)] = TileType::PowerUp(PowerUpType::Kick);
+        map[IVec2::new(3, 1)] =
+            TileType::BreakableWall(Box::new(TileType::PowerUp(PowerUpType::Glove)));
+        map[IVec2::new(4, 1)] = TileType::PowerUp(PowerUpType::Speed);
+        let rules = Ruleset {
+            power_ups: vec![(PowerUpType::Speed, 0.1)],
+            ..Ruleset::classic()
+        };
+        let sim = GameSim::with_rules(map, rules);
+        assert_eq!(sim.tiles()[IVec2::new(2, 1)], TileType::Empty);
+        assert_eq!(
+            sim.tiles()[IVec2::new(3, 1)],
+            TileType::BreakableWall(Box::new(TileType::Empty))
+        );
+        assert_eq!(
+            sim.tiles()[IVec2::new(4, 1)],
+            TileType::PowerUp(PowerUpType::Speed)
+        );
+    }
+
+    #[test]
+    fn test_crushed_bomb_returns_to_owner() {
+        let rules = Ruleset {
+            sudden_death: Some(10),
+            ..Ruleset::classic()
+        };
+        let map = open_map(7, 3, vec![IVec2::new(5, 1), IVec2::new(4, 1)]);
+        let mut sim = GameSim::with_rules(map, rules);
+        sim.add_player(PlayerController::KeyboardArrows).unwrap();
+        sim.add_player(PlayerController::KeyboardWASD).unwrap();
+
+        // A remote bomb waits on the first tile of the spiral
+        let mut bomb = Bomb::new(BOMB_EXPLOSION_DELAY, 1, 0);
+        bomb.remote = Some(0);
+        sim.tiles_mut()[IVec2::new(1, 1)] = TileType::Bomb(Some(bomb));
+        sim.players[0].num_bombs -= 1;
+        assert_eq!(sim.next_remote_bomb(0), Some(IVec2::new(1, 1)));
+
+        let events = run_for(&mut sim, 11);
+        assert!(events.contains(&SimEvent::WallDropped(IVec2::new(1, 1))));
+        assert_eq!(sim.tiles()[IVec2::new(1, 1)], TileType::SolidWall);
+        assert_eq!(sim.players()[0].num_bombs, 1);
+        assert_eq!(sim.next_remote_bomb(0), None);
+    }
+
+    #[test]
+    fn test_fast_players_stay_out_of_walls() {
+        // Pillars on every other tile, and a row of speed power-ups
+        let mut map = open_map(9, 9, vec![IVec2::new(1, 1)]);
+        for pos in map.pos_iter() {
+            if pos.x % 2 == 0 && pos.y % 2 == 0 {
+                map[pos] = TileType::SolidWall;
+            }
+        }
+        for x in 2..8 {
+            map[IVec2::new(x, 1)] = TileType::PowerUp(PowerUpType::Speed);
+        }
+        let mut sim = GameSim::new(map);
+        sim.add_player(PlayerController::KeyboardArrows).unwrap();
+        let walk = |motion: Vec2| PlayerControl {
+            motion: motion.normalize(),
+            action: PlayerAction::None,
+        };
+
+        for _ in 0..100 {
+            sim.step(&[walk(Vec2::X)]);
+        }
+        assert_eq!(sim.players()[0].speed, sim.rules().max_player_speed);
+
+        // Run diagonally into pillar corners and along the walls
+        for motion in [Vec2::ONE, Vec2::NEG_Y, Vec2::new(-1.0, 1.0), Vec2::NEG_ONE, Vec2::Y] {
+            for _ in 0..40 {
+                sim.step(&[walk(motion)]);
+                let tiles = sim.tiles();
+                let pos = tiles.get_index_from_position(sim.players()[0].position);
+                assert!(pos.is_some_and(|pos| tiles[pos] == TileType::Empty));
+            }
+        }
+    }
+}
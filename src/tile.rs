@@ -1,19 +1,64 @@
 use bevy::prelude::*;
-use web_time::Instant;
+use serde::{Deserialize, Serialize};
 
 pub const TILE_SIZE: f32 = 1.0;
 
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub struct Bomb {
-    pub when_to_explode: Instant,
+    // Ticks remaining until the bomb explodes
+    pub fuse: u32,
     pub firepower: i32,
-    pub player_entity: Entity,
+    pub player_index: usize,
+    // Set while a kicked bomb is moving
+    pub slide: Option<Slide>,
+    // The owner's `Player::bombs_placed` when a remote bomb was laid. Its fuse
+    // doesn't burn; it waits for its owner to set it off.
+    pub remote: Option<u64>,
+    // The blast burns through breakable walls instead of stopping at them
+    pub pierce: bool,
 }
 
+impl Bomb {
+    pub fn new(fuse: u32, firepower: i32, player_index: usize) -> Self {
+        Self {
+            fuse,
+            firepower,
+            player_index,
+            slide: None,
+            remote: None,
+            pierce: false,
+        }
+    }
+}
+
+// A kicked bomb moves one tile at a time in `direction` until blocked
 #[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Slide {
+    pub direction: IVec2,
+    // Ticks spent moving towards the next tile
+    pub progress: u32,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
 pub enum PowerUpType {
     Firepower,
     ExtraBomb,
+    // Walking into a bomb sends it sliding
+    Kick,
+    // Bombs can be lifted and thrown over walls
+    Glove,
+    // Bombs wait to be set off with the detonate button
+    Remote,
+    // Raises the player's speed, up to a cap
+    Speed,
+    // Blasts go through breakable walls
+    Pierce,
+    // Dropping a bomb while standing on one lays out a line of bombs
+    LineBomb,
+    // Curses the player for a while, see `player::Curse`
+    Skull,
+    // Absorbs one explosion, after which the player can't be hurt for a moment
+    Shield,
 }
 
 #[derive(Clone, PartialEq, Eq, Hash, Debug, Default)]
@@ -23,7 +68,8 @@ pub enum TileType {
     SolidWall,
     BreakableWall(Box<TileType>),
     Bomb(Option<Bomb>),
-    Explosion(Option<Instant>, Box<TileType>),
+    // Ticks remaining until the explosion clears, and what it leaves behind
+    Explosion(Option<u32>, Box<TileType>),
     PowerUp(PowerUpType),
 }
 
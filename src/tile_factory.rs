@@ -1,5 +1,6 @@
 use bevy::prelude::*;
 use rand::Rng;
+use std::collections::HashMap;
 
 use crate::{models::*, tile::*};
 
@@ -8,6 +9,7 @@ pub enum GameAsset {
     None,
     Scene(SceneBundle),
     AnimatedScene((SceneBundle, Handle<AnimationGraph>)),
+    Mesh(PbrBundle),
 }
 
 #[derive(Resource)]
@@ -18,14 +20,38 @@ pub struct ResourceTileFactory {
     asset_breakable_wall: Vec<GameAsset>,
     asset_firepower: GameAsset,
     asset_extrabomb: GameAsset,
+    // Power-ups without a model of their own are drawn as coloured tokens
+    asset_tokens: HashMap<PowerUpType, GameAsset>,
 }
 
 impl ResourceTileFactory {
     pub fn new(
         animation_graphs: &mut ResMut<Assets<AnimationGraph>>,
         asset_server: &Res<AssetServer>,
+        meshes: &mut ResMut<Assets<Mesh>>,
+        materials: &mut ResMut<Assets<StandardMaterial>>,
     ) -> Self {
+        let token_mesh = meshes.add(Cylinder::new(0.3 * TILE_SIZE, 0.1 * TILE_SIZE));
+        let mut token = |color: Color| {
+            GameAsset::Mesh(PbrBundle {
+                mesh: token_mesh.clone(),
+                material: materials.add(color),
+                ..default()
+            })
+        };
+        let asset_tokens = HashMap::from([
+            (PowerUpType::Kick, token(Color::srgb(0.2, 0.4, 1.0))),
+            (PowerUpType::Glove, token(Color::srgb(1.0, 0.6, 0.8))),
+            (PowerUpType::Remote, token(Color::srgb(0.9, 0.1, 0.1))),
+            (PowerUpType::Speed, token(Color::srgb(1.0, 0.9, 0.1))),
+            (PowerUpType::Pierce, token(Color::srgb(0.6, 0.2, 0.9))),
+            (PowerUpType::LineBomb, token(Color::srgb(1.0, 0.5, 0.0))),
+            (PowerUpType::Skull, token(Color::srgb(0.1, 0.1, 0.1))),
+            (PowerUpType::Shield, token(Color::srgb(0.2, 0.9, 0.9))),
+        ]);
+
         Self {
+            asset_tokens,
             asset_unbreakable_wall: GameAsset::Scene(SceneBundle {
                 scene: asset_server
                     .load(GltfAssetLabel::Scene(0).from_asset(MODEL_CUBE_BRICK_PATH)),
@@ -84,23 +110,28 @@ impl ResourceTileFactory {
         }
     }
 
-    pub fn make_tile(&self, tile_type: &TileType) -> GameAsset {
+    pub fn make_tile(&self, tile_type: &TileType, rng: &mut impl Rng) -> GameAsset {
         match tile_type {
             TileType::Empty => GameAsset::None,
             TileType::SolidWall => self.asset_unbreakable_wall.clone(),
             TileType::BreakableWall(_) => {
-                let index = rand::thread_rng().gen_range(0..self.asset_breakable_wall.len());
+                let index = rng.gen_range(0..self.asset_breakable_wall.len());
                 self.asset_breakable_wall[index].clone()
             }
             TileType::Bomb(_) => self.asset_bomb.clone(),
             TileType::Explosion(_, _) => self.asset_explosion.clone(),
             TileType::PowerUp(PowerUpType::Firepower) => self.asset_firepower.clone(),
             TileType::PowerUp(PowerUpType::ExtraBomb) => self.asset_extrabomb.clone(),
+            TileType::PowerUp(power_up) => self
+                .asset_tokens
+                .get(power_up)
+                .cloned()
+                .unwrap_or(GameAsset::None),
         }
     }
 }
 
-pub fn random_orthogonal_rotation() -> Quat {
+pub fn random_orthogonal_rotation(rng: &mut impl Rng) -> Quat {
     use rand::seq::SliceRandom;
 
     let rotations = vec![
@@ -116,6 +147,5 @@ pub fn random_orthogonal_rotation() -> Quat {
         Quat::from_rotation_z(std::f32::consts::PI),
     ];
 
-    let mut rng = rand::thread_rng();
-    *rotations.choose(&mut rng).unwrap()
+    *rotations.choose(rng).unwrap()
 }
@@ -0,0 +1,178 @@
+use crate::bot::*;
+use crate::map::TileGrid;
+use crate::player::*;
+use crate::rules::Ruleset;
+use crate::sim::*;
+use crate::tile::*;
+use std::collections::HashMap;
+
+// How one bot-only match ended
+#[derive(Clone, Debug)]
+pub struct MatchResult {
+    // `None` if nobody had won when the time ran out
+    pub outcome: Option<Outcome>,
+    pub num_players: usize,
+    pub ticks: u64,
+    pub pickups: HashMap<PowerUpType, u32>,
+}
+
+// Plays a match between bots on the given map, one per spawn point in order.
+// `seed` decides the match's random choices, such as curses.
+pub fn play_bot_match(
+    tiles: TileGrid,
+    seed: u64,
+    num_players: usize,
+    difficulty: BotDifficulty,
+    max_ticks: u64,
+    rules: &Ruleset,
+) -> MatchResult {
+    let mut sim = GameSim::with_rules(tiles, rules.clone());
+    sim.set_seed(seed);
+    for _ in 0..num_players {
+        sim.add_player(PlayerController::Bot(difficulty));
+    }
+
+    let mut pickups = HashMap::new();
+    while sim.tick() < max_ticks && sim.outcome().is_none() {
+        let controls: Vec<_> = (0..sim.players().len())
+            .map(|player_index| bot_control(&sim, player_index))
+            .collect();
+        for event in sim.step(&controls) {
+            if let SimEvent::PowerUpCollected(_, power_up) = event {
+                *pickups.entry(power_up).or_insert(0) += 1;
+            }
+        }
+    }
+
+    MatchResult {
+        outcome: sim.outcome(),
+        num_players: sim.players().len(),
+        ticks: sim.tick(),
+        pickups,
+    }
+}
+
+// Totals over many matches
+#[derive(Clone, Debug, Default)]
+pub struct TournamentStats {
+    pub matches: u32,
+    // Indexed by spawn point
+    pub wins: Vec<u32>,
+    pub draws: u32,
+    pub timeouts: u32,
+    pub total_ticks: u64,
+    pub pickups: HashMap<PowerUpType, u32>,
+}
+
+impl TournamentStats {
+    pub fn add(&mut self, result: &MatchResult) {
+        self.matches += 1;
+        self.total_ticks += result.ticks;
+        if self.wins.len() < result.num_players {
+            self.wins.resize(result.num_players, 0);
+        }
+        match result.outcome {
+            Some(Outcome::Win(player_index)) => self.wins[player_index] += 1,
+            Some(Outcome::Draw) => self.draws += 1,
+            None => self.timeouts += 1,
+        }
+        for (power_up, count) in &result.pickups {
+            *self.pickups.entry(*power_up).or_insert(0) += count;
+        }
+    }
+
+    pub fn merge(&mut self, other: &TournamentStats) {
+        self.matches += other.matches;
+        if self.wins.len() < other.wins.len() {
+            self.wins.resize(other.wins.len(), 0);
+        }
+        for (wins, other_wins) in self.wins.iter_mut().zip(&other.wins) {
+            *wins += other_wins;
+        }
+        self.draws += other.draws;
+        self.timeouts += other.timeouts;
+        self.total_ticks += other.total_ticks;
+        for (power_up, count) in &other.pickups {
+            *self.pickups.entry(*power_up).or_insert(0) += count;
+        }
+    }
+}
+
+impl std::fmt::Display for TournamentStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let matches = self.matches.max(1) as f32;
+        let percent = |count: u32| 100.0 * count as f32 / matches;
+
+        writeln!(f, "Matches: {}", self.matches)?;
+        for (spawn_index, wins) in self.wins.iter().enumerate() {
+            writeln!(
+                f,
+                "Spawn point {}: {} wins ({:.1}%)",
+                spawn_index,
+                wins,
+                percent(*wins)
+            )?;
+        }
+        writeln!(f, "Draws: {} ({:.1}%)", self.draws, percent(self.draws))?;
+        writeln!(
+            f,
+            "Timeouts: {} ({:.1}%)",
+            self.timeouts,
+            percent(self.timeouts)
+        )?;
+        writeln!(
+            f,
+            "Average match length: {:.1} s",
+            self.total_ticks as f32 / matches / TICKS_PER_SECOND as f32
+        )?;
+
+        let mut pickups: Vec<_> = self.pickups.iter().collect();
+        pickups.sort_by_key(|(power_up, _)| format!("{:?}", power_up));
+        for (power_up, count) in pickups {
+            writeln!(
+                f,
+                "{:?} pickups: {} ({:.2} per match)",
+                power_up,
+                count,
+                *count as f32 / matches
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::basic::*;
+    use crate::map::MapSeed;
+
+    #[test]
+    fn test_tournament_counts_every_match() {
+        let mut stats = TournamentStats::default();
+        for seed in 0..2 {
+            let tiles =
+                make_basic_map(MAP_DIMENSIONS.0, MAP_DIMENSIONS.1, &mut MapSeed(seed).rng());
+            let result = play_bot_match(
+                tiles,
+                seed,
+                4,
+                BotDifficulty::Easy,
+                20 * TICKS_PER_SECOND as u64,
+                &Ruleset::default(),
+            );
+            assert!(result.ticks <= 20 * TICKS_PER_SECOND as u64);
+            stats.add(&result);
+        }
+
+        let mut total = TournamentStats::default();
+        total.merge(&stats);
+        total.merge(&stats);
+        assert_eq!(total.matches, 4);
+        assert_eq!(
+            total.wins.iter().sum::<u32>() + total.draws + total.timeouts,
+            total.matches
+        );
+        assert!(total.to_string().starts_with("Matches: 4\n"));
+    }
+}